@@ -0,0 +1,1122 @@
+use anyhow::{anyhow, Result};
+use wasm_bindgen::prelude::*;
+use crate::audio;
+use crate::clock::Region;
+
+// length counter loads, indexed by bits 3-7 of $4003/$4007/$400B/$400F
+static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30
+];
+
+static DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1]
+];
+
+static TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+// noise timer periods in CPU cycles
+static NOISE_PERIODS_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068
+];
+static NOISE_PERIODS_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778
+];
+
+// DMC output unit periods in CPU cycles
+static DMC_RATES_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54
+];
+static DMC_RATES_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50
+];
+
+// CPU cycles of the frame sequencer steps: three quarter frames, the end
+// of the 4-step sequence and the end of the 5-step sequence
+static FRAME_STEPS_NTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+static FRAME_STEPS_PAL: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+// longest stretch of CPU cycles resampled in one go when the host does
+// not end audio frames itself
+static AUDIO_FRAME_CYCLES: u32 = 8192;
+static DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// the 2A03's own channels, expansion audio is not emulated
+#[wasm_bindgen]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC
+}
+
+impl Channel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::DMC => "dmc"
+        }
+    }
+}
+
+// every channel, in the order `Channel as usize` indexes mixer settings
+pub static CHANNELS: [Channel; 5] = [
+    Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::DMC
+];
+
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    value: u8
+}
+
+impl LengthCounter {
+    // loads are ignored while the channel is disabled through $4015
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.value > 0
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // constant volume, or the divider period when decaying
+    volume: u8,
+    divider: u8,
+    decay: u8
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {self.volume} else {self.decay}
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    // pulse 1 negates its sweep with one's complement, pulse 2 with two's
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            ..Pulse::default()
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            },
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            },
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> i32 {
+        let period = self.period as i32;
+        let change = period >> self.sweep_shift;
+        if self.sweep_negate {
+            period - change - self.ones_complement as i32
+        } else {
+            period + change
+        }
+    }
+
+    // the sweep unit silences the channel even when it is disabled
+    fn sweep_muting(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.sweep_muting() {
+            self.period = self.sweep_target().max(0) as u16;
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.sweep_muting() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                // the control flag doubles as the length counter halt
+                self.length.halt = data & 0x80 != 0;
+                self.linear_reload_value = data & 0x7F;
+            },
+            1 => {},
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle, the sequencer only moves while both
+    // counters are non-zero
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.length.halt {
+            self.linear_reload = false;
+        }
+    }
+
+    // a halted triangle keeps holding its last level
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+struct Noise {
+    periods: &'static [u16; 16],
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+    length: LengthCounter,
+    envelope: Envelope
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            periods: &NOISE_PERIODS_NTSC,
+            short_mode: false,
+            period: NOISE_PERIODS_NTSC[0],
+            timer: 0,
+            // the shift register powers up as 1
+            shift: 1,
+            length: LengthCounter::default(),
+            envelope: Envelope::default()
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            },
+            1 => {},
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.period = self.periods[(data & 0x0F) as usize];
+            },
+            _ => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle, the periods are in CPU cycles
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            // mode 1 takes its feedback from bit 6, giving 93 step loops
+            let tap = if self.short_mode {6} else {1};
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+struct Dmc {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    level: u8,
+    // $4012/$4013 as addresses and byte counts
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq: bool
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Dmc {
+            rates: &DMC_RATES_NTSC,
+            irq_enabled: false,
+            looping: false,
+            period: DMC_RATES_NTSC[0],
+            timer: 0,
+            level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.period = self.rates[(data & 0x0F) as usize];
+            },
+            1 => self.level = data & 0x7F,
+            2 => self.sample_addr = 0xC000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 1
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // address the memory reader wants fetched into the empty sample buffer
+    fn dma_request(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    fn fill_buffer(&mut self, data: u8) {
+        if self.bytes_remaining == 0 {
+            debug!("dmc sample byte dropped, no fetch was requested");
+            return;
+        }
+        self.buffer = Some(data);
+        // the address wraps from $FFFF around to $8000
+        self.current_addr = if self.current_addr == 0xFFFF {0x8000} else {self.current_addr + 1};
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle, the rates are in CPU cycles
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+        if !self.silence {
+            // the level moves by 2 and stays within 0-127
+            if self.shift & 0x01 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                },
+                None => self.silence = true
+            }
+        }
+    }
+}
+
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    cycles: u64,
+    frame_steps: &'static [u32; 5],
+    // frame counter state, see write_frame_counter
+    frame_cycles: u32,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_reset_delay: Option<u8>,
+    // mixer output and the cycles since the last end_audio_frame
+    audio: audio::AudioOutput,
+    audio_cycles: u32,
+    mix_level: f32,
+    // per channel mixer settings, indexed by `Channel as usize`
+    channel_enabled: [bool; 5],
+    channel_gain: [f32; 5],
+    solo: Option<Channel>,
+    // one stream per channel while capturing, empty otherwise
+    captures: Vec<audio::AudioOutput>,
+    capture_levels: [f32; 5]
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            cycles: 0,
+            frame_steps: &FRAME_STEPS_NTSC,
+            frame_cycles: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_reset_delay: None,
+            audio: audio::AudioOutput::new(Region::NTSC.cpu_clock_hz(), DEFAULT_SAMPLE_RATE),
+            audio_cycles: 0,
+            mix_level: 0.0,
+            channel_enabled: [true; 5],
+            channel_gain: [1.0; 5],
+            solo: None,
+            captures: Vec::new(),
+            capture_levels: [0.0; 5]
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        let (noise, dmc, frame) = match region {
+            Region::NTSC | Region::Dendy => (&NOISE_PERIODS_NTSC, &DMC_RATES_NTSC, &FRAME_STEPS_NTSC),
+            Region::PAL => (&NOISE_PERIODS_PAL, &DMC_RATES_PAL, &FRAME_STEPS_PAL)
+        };
+        self.noise.periods = noise;
+        self.dmc.rates = dmc;
+        self.frame_steps = frame;
+        self.audio.set_clock_rate(region.cpu_clock_hz());
+        for capture in self.captures.iter_mut() {
+            capture.set_clock_rate(region.cpu_clock_hz());
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.audio.set_sample_rate(sample_rate);
+        for capture in self.captures.iter_mut() {
+            capture.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn audio(&self) -> &audio::AudioOutput {
+        &self.audio
+    }
+
+    pub fn audio_mut(&mut self) -> &mut audio::AudioOutput {
+        &mut self.audio
+    }
+
+    // makes every sample up to the current cycle available for reading
+    pub fn end_audio_frame(&mut self) {
+        self.audio.end_frame(self.audio_cycles);
+        for capture in self.captures.iter_mut() {
+            capture.end_frame(self.audio_cycles);
+        }
+        self.audio_cycles = 0;
+    }
+
+    // a disabled channel is left out of the mix, unless it is soloed
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.channel_enabled[channel as usize] = enabled;
+    }
+
+    pub fn channel_enabled(&self, channel: Channel) -> bool {
+        self.channel_enabled[channel as usize]
+    }
+
+    // scales the channel's level before it enters the mixer
+    pub fn set_channel_gain(&mut self, channel: Channel, gain: f32) {
+        self.channel_gain[channel as usize] = gain;
+    }
+
+    pub fn channel_gain(&self, channel: Channel) -> f32 {
+        self.channel_gain[channel as usize]
+    }
+
+    // mixes only `channel` while set, whatever the enable flags say
+    pub fn set_solo(&mut self, solo: Option<Channel>) {
+        self.solo = solo;
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    // records every channel on its own, before enable, gain and mixing
+    // with the others, at the rate of the main output
+    pub fn set_channel_capture(&mut self, capture: bool) {
+        self.captures.clear();
+        self.capture_levels = [0.0; 5];
+        if capture {
+            let (clock_rate, sample_rate) = (self.audio.clock_rate(), self.audio.sample_rate());
+            self.captures = CHANNELS.iter()
+                .map(|_| audio::AudioOutput::new(clock_rate, sample_rate))
+                .collect();
+        }
+    }
+
+    pub fn channel_capture(&self) -> bool {
+        !self.captures.is_empty()
+    }
+
+    // the captured stream of `channel`, None when not capturing
+    pub fn channel_audio_mut(&mut self, channel: Channel) -> Option<&mut audio::AudioOutput> {
+        self.captures.get_mut(channel as usize)
+    }
+
+    // CPU writes to $4000-$4013 and $4015
+    pub fn write_register(&mut self, addr: u16, data: u8) -> Result<()> {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr & 0x03, data),
+            0x4004..=0x4007 => self.pulse2.write(addr & 0x03, data),
+            0x4008..=0x400B => self.triangle.write(addr & 0x03, data),
+            0x400C..=0x400F => self.noise.write(addr & 0x03, data),
+            0x4010..=0x4013 => self.dmc.write(addr & 0x03, data),
+            0x4015 => self.write_status(data),
+            0x4017 => self.write_frame_counter(data),
+            addr => return Err(anyhow!("unknown apu register: {:#06x}", addr))
+        }
+        Ok(())
+    }
+
+    fn write_status(&mut self, data: u8) {
+        self.pulse1.length.set_enabled(data & 0x01 != 0);
+        self.pulse2.length.set_enabled(data & 0x02 != 0);
+        self.triangle.length.set_enabled(data & 0x04 != 0);
+        self.noise.length.set_enabled(data & 0x08 != 0);
+        self.dmc.set_enabled(data & 0x10 != 0);
+    }
+
+    // bit 7 selects the 5-step sequence, bit 6 inhibits the frame IRQ
+    fn write_frame_counter(&mut self, data: u8) {
+        self.five_step = data & 0x80 != 0;
+        self.irq_inhibit = data & 0x40 != 0;
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+        // the sequencer restarts 3 CPU cycles later when written on the
+        // first half of an APU cycle and 4 cycles later otherwise
+        self.frame_reset_delay = Some(if self.cycles & 0x01 == 0 {3} else {4});
+    }
+
+    // $4015 reads, acknowledging the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    // $4015 without the side effects of reading it
+    pub fn peek_status(&self) -> u8 {
+        (self.pulse1.length.active() as u8) |
+            (self.pulse2.length.active() as u8) << 1 |
+            (self.triangle.length.active() as u8) << 2 |
+            (self.noise.length.active() as u8) << 3 |
+            ((self.dmc.bytes_remaining > 0) as u8) << 4 |
+            (self.frame_irq as u8) << 6 |
+            (self.dmc.irq as u8) << 7
+    }
+
+    // level of the APU's IRQ output
+    pub fn irq_line(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // address of the sample byte the DMC wants, the CPU halts to fetch it
+    // and hands it over through complete_dmc_dma
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn complete_dmc_dma(&mut self, data: u8) {
+        self.dmc.fill_buffer(data);
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_reset_delay {
+            if delay > 1 {
+                self.frame_reset_delay = Some(delay - 1);
+            } else {
+                self.frame_reset_delay = None;
+                self.frame_cycles = 0;
+                // switching to 5-step mode clocks everything right away
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                return;
+            }
+        }
+        self.frame_cycles += 1;
+        let steps = self.frame_steps;
+        let cycle = self.frame_cycles;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.quarter_frame();
+        } else if cycle == steps[1] {
+            self.quarter_frame();
+            self.half_frame();
+        } else if !self.five_step {
+            // the IRQ flag is raised on the last three cycles
+            if cycle + 1 >= steps[3] && cycle <= steps[3] + 1 && !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+            if cycle == steps[3] {
+                self.quarter_frame();
+                self.half_frame();
+            } else if cycle == steps[3] + 1 {
+                self.frame_cycles = 0;
+            }
+        } else if cycle == steps[4] {
+            self.quarter_frame();
+            self.half_frame();
+        } else if cycle == steps[4] + 1 {
+            self.frame_cycles = 0;
+        }
+    }
+
+    // advances the APU by one CPU cycle
+    pub fn step(&mut self) {
+        self.clock_frame_counter();
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles & 0x01 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.cycles += 1;
+        self.update_mix();
+    }
+
+    // feeds level changes of the mixed output to the resampler
+    fn update_mix(&mut self) {
+        let levels = CHANNELS.map(|channel| self.channel_output(channel) as f32);
+        let mut gained = [0.0; 5];
+        for (index, channel) in CHANNELS.iter().enumerate() {
+            let audible = match self.solo {
+                Some(solo) => solo == *channel,
+                None => self.channel_enabled[index]
+            };
+            if audible {
+                gained[index] = levels[index] * self.channel_gain[index];
+            }
+        }
+        let level = audio::mix(gained[0], gained[1], gained[2], gained[3], gained[4]);
+        if !self.captures.is_empty() {
+            self.update_captures(&levels);
+        }
+        if level != self.mix_level {
+            self.audio.add_delta(self.audio_cycles, level - self.mix_level);
+            self.mix_level = level;
+        }
+        self.audio_cycles += 1;
+        if self.audio_cycles >= AUDIO_FRAME_CYCLES {
+            self.end_audio_frame();
+        }
+    }
+
+    // each stem goes through the mixer on its own so it has the level it
+    // contributes to the full mix
+    fn update_captures(&mut self, levels: &[f32; 5]) {
+        for (index, capture) in self.captures.iter_mut().enumerate() {
+            let mut alone = [0.0; 5];
+            alone[index] = levels[index];
+            let level = audio::mix(alone[0], alone[1], alone[2], alone[3], alone[4]);
+            if level != self.capture_levels[index] {
+                capture.add_delta(self.audio_cycles, level - self.capture_levels[index]);
+                self.capture_levels[index] = level;
+            }
+        }
+    }
+
+    // envelopes and the triangle linear counter
+    pub fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear();
+        self.noise.envelope.clock();
+    }
+
+    // length counters and sweep units
+    pub fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // current 4-bit output level of a channel
+    pub fn channel_output(&self, channel: Channel) -> u8 {
+        match channel {
+            Channel::Pulse1 => self.pulse1.output(),
+            Channel::Pulse2 => self.pulse2.output(),
+            Channel::Triangle => self.triangle.output(),
+            Channel::Noise => self.noise.output(),
+            Channel::DMC => self.dmc.level
+        }
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_counters_follow_status() {
+        let mut apu = Apu::new();
+        // loads are dropped while the channel is disabled
+        apu.write_register(0x4003, 0x08).unwrap();
+        assert_eq!(apu.read_status(), 0x00);
+        apu.write_register(0x4015, 0x0F).unwrap();
+        apu.write_register(0x4003, 0x08).unwrap();
+        apu.write_register(0x400B, 0x08).unwrap();
+        assert_eq!(apu.read_status(), 0x05);
+        // index 1 loads 254, a halted counter stays put
+        apu.write_register(0x4008, 0x80).unwrap();
+        for _ in 0..254 {
+            apu.half_frame();
+        }
+        assert_eq!(apu.read_status(), 0x04);
+        apu.write_register(0x4015, 0x00).unwrap();
+        assert_eq!(apu.read_status(), 0x00);
+    }
+
+    #[test]
+    fn pulse_one_negates_with_ones_complement() {
+        let mut apu = Apu::new();
+        for base in [0x4000, 0x4004] {
+            apu.write_register(base + 1, 0x89).unwrap();
+            apu.write_register(base + 2, 0x00).unwrap();
+            apu.write_register(base + 3, 0x01).unwrap();
+        }
+        assert_eq!(apu.pulse1.sweep_target(), 0x0100 - 0x80 - 1);
+        assert_eq!(apu.pulse2.sweep_target(), 0x0100 - 0x80);
+        apu.half_frame();
+        assert_eq!((apu.pulse1.period, apu.pulse2.period), (0x7F, 0x80));
+    }
+
+    #[test]
+    fn sweep_mutes_out_of_range_periods() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x01).unwrap();
+        // constant volume 15, 75% duty starts high
+        apu.write_register(0x4000, 0xFF).unwrap();
+        apu.write_register(0x4002, 0x07).unwrap();
+        apu.write_register(0x4003, 0x08).unwrap();
+        assert_eq!(apu.channel_output(Channel::Pulse1), 0);
+        apu.write_register(0x4002, 0x08).unwrap();
+        assert_eq!(apu.channel_output(Channel::Pulse1), 15);
+        // an upward sweep past $7FF mutes, even with the unit disabled
+        apu.write_register(0x4001, 0x00).unwrap();
+        apu.write_register(0x4003, 0x0F).unwrap();
+        apu.write_register(0x4002, 0xFF).unwrap();
+        assert_eq!(apu.channel_output(Channel::Pulse1), 0);
+    }
+
+    #[test]
+    fn envelope_decays_and_loops() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x08).unwrap();
+        apu.write_register(0x400C, 0x20).unwrap();
+        apu.write_register(0x400F, 0x08).unwrap();
+        apu.quarter_frame();
+        assert_eq!(apu.noise.envelope.output(), 15);
+        for _ in 0..15 {
+            apu.quarter_frame();
+        }
+        assert_eq!(apu.noise.envelope.output(), 0);
+        apu.quarter_frame();
+        assert_eq!(apu.noise.envelope.output(), 15);
+    }
+
+    #[test]
+    fn triangle_needs_linear_counter() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x04).unwrap();
+        apu.write_register(0x4008, 0x02).unwrap();
+        apu.write_register(0x400A, 0x00).unwrap();
+        apu.write_register(0x400B, 0x08).unwrap();
+        apu.step();
+        assert_eq!(apu.triangle.step, 0);
+        apu.quarter_frame();
+        for _ in 0..4 {
+            apu.step();
+        }
+        assert_eq!(apu.channel_output(Channel::Triangle), TRIANGLE_TABLE[4]);
+        // two more quarter frames run the linear counter out
+        apu.quarter_frame();
+        apu.quarter_frame();
+        apu.step();
+        assert_eq!(apu.triangle.step, 4);
+    }
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.step();
+        }
+    }
+
+    // pulse 1 with a 254 step length counter, counting half frames
+    fn half_frame_counter() -> Apu {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x01).unwrap();
+        apu.write_register(0x4003, 0x08).unwrap();
+        apu
+    }
+
+    #[test]
+    fn four_step_sequence_raises_irq() {
+        let mut apu = half_frame_counter();
+        run(&mut apu, 14912);
+        assert_eq!(apu.pulse1.length.value, 254);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse1.length.value, 253);
+        run(&mut apu, 29827 - 14913);
+        assert!(!apu.irq_line());
+        run(&mut apu, 1);
+        assert!(apu.irq_line());
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse1.length.value, 252);
+        // reading $4015 acknowledges, but the flag is set once more
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq_line());
+        run(&mut apu, 1);
+        assert!(apu.irq_line());
+        // the next sequence starts right away
+        apu.read_status();
+        run(&mut apu, 14912);
+        assert_eq!(apu.pulse1.length.value, 252);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse1.length.value, 251);
+        assert!(!apu.irq_line());
+    }
+
+    #[test]
+    fn inhibit_blocks_and_clears_irq() {
+        let mut apu = Apu::new();
+        run(&mut apu, 29830);
+        assert!(apu.irq_line());
+        apu.write_register(0x4017, 0x40).unwrap();
+        assert!(!apu.irq_line());
+        run(&mut apu, 29830 * 2);
+        assert!(!apu.irq_line());
+    }
+
+    #[test]
+    fn five_step_sequence_clocks_on_write() {
+        let mut apu = half_frame_counter();
+        // written on an even cycle, the reset lands three cycles later
+        apu.write_register(0x4017, 0x80).unwrap();
+        run(&mut apu, 2);
+        assert_eq!(apu.pulse1.length.value, 254);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse1.length.value, 253);
+        run(&mut apu, 37281);
+        assert_eq!(apu.pulse1.length.value, 251);
+        assert!(!apu.irq_line());
+
+        // on an odd cycle it takes four
+        let mut apu = half_frame_counter();
+        run(&mut apu, 1);
+        apu.write_register(0x4017, 0x80).unwrap();
+        run(&mut apu, 3);
+        assert_eq!(apu.pulse1.length.value, 254);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse1.length.value, 253);
+    }
+
+    #[test]
+    fn dmc_plays_fetched_bytes() {
+        let mut apu = Apu::new();
+        // fastest rate, sample at $C040, 17 bytes
+        apu.write_register(0x4010, 0x0F).unwrap();
+        apu.write_register(0x4011, 0x40).unwrap();
+        apu.write_register(0x4012, 0x01).unwrap();
+        apu.write_register(0x4013, 0x01).unwrap();
+        assert_eq!(apu.dmc_dma_request(), None);
+        apu.write_register(0x4015, 0x10).unwrap();
+        assert_eq!(apu.dmc_dma_request(), Some(0xC040));
+        apu.complete_dmc_dma(0x0F);
+        assert_eq!(apu.dmc_dma_request(), None);
+        assert_eq!(apu.read_status() & 0x10, 0x10);
+
+        // the first output cycle finds the sample buffer only at its end
+        run(&mut apu, 54 * 8);
+        assert_eq!(apu.channel_output(Channel::DMC), 0x40);
+        assert_eq!(apu.dmc_dma_request(), Some(0xC041));
+        run(&mut apu, 54 * 4);
+        assert_eq!(apu.channel_output(Channel::DMC), 0x48);
+        run(&mut apu, 54 * 4);
+        assert_eq!(apu.channel_output(Channel::DMC), 0x40);
+    }
+
+    #[test]
+    fn dmc_irq_and_loop() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4010, 0x80).unwrap();
+        apu.write_register(0x4013, 0x00).unwrap();
+        apu.write_register(0x4015, 0x10).unwrap();
+        apu.complete_dmc_dma(0x00);
+        assert!(apu.irq_line());
+        assert_eq!(apu.read_status() & 0x90, 0x80);
+        // writing $4015 acknowledges it
+        apu.write_register(0x4015, 0x00).unwrap();
+        assert!(!apu.irq_line());
+
+        apu.write_register(0x4010, 0xC0).unwrap();
+        apu.write_register(0x4015, 0x10).unwrap();
+        apu.complete_dmc_dma(0x00);
+        assert!(!apu.irq_line());
+        assert_eq!(apu.read_status() & 0x10, 0x10);
+    }
+
+    #[test]
+    fn noise_lfsr_loop_lengths() {
+        for (mode, expected) in [(0x00, 32767), (0x80, 93)] {
+            let mut noise = Noise::new();
+            noise.write(2, mode);
+            let mut steps = 0;
+            loop {
+                noise.timer = 0;
+                noise.clock_timer();
+                steps += 1;
+                if noise.shift == 1 {
+                    break;
+                }
+            }
+            assert_eq!(steps, expected);
+        }
+    }
+
+    #[test]
+    fn pulse_reaches_the_audio_output() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(48_000);
+        apu.write_register(0x4015, 0x01).unwrap();
+        // constant volume 15, 50% duty, a 440 Hz tone
+        apu.write_register(0x4000, 0xBF).unwrap();
+        apu.write_register(0x4002, 0xFD).unwrap();
+        apu.write_register(0x4003, 0x08).unwrap();
+        for _ in 0..29_830 {
+            apu.step();
+        }
+        apu.end_audio_frame();
+        let mut out = vec![0.0; 1000];
+        let count = apu.audio_mut().read_samples(&mut out);
+        assert_eq!(count, 800);
+        // skip the pop of the triangle's idle level of 15 fading out
+        let peak = out[400..800].iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak > 0.05 && peak < 0.2, "peak {}", peak);
+    }
+
+    #[test]
+    fn channels_can_be_muted_soloed_and_captured() {
+        let mut apu = Apu::new();
+        apu.set_channel_capture(true);
+        apu.write_register(0x4015, 0x01).unwrap();
+        apu.write_register(0x4000, 0xBF).unwrap();
+        apu.write_register(0x4002, 0xFD).unwrap();
+        apu.write_register(0x4003, 0x08).unwrap();
+        // only the muted pulse is playing, the triangle holds its level
+        apu.set_channel_enabled(Channel::Pulse1, false);
+        apu.set_channel_gain(Channel::Triangle, 0.0);
+        for _ in 0..29_830 {
+            apu.step();
+        }
+        assert_eq!(apu.mix_level, 0.0);
+        apu.set_solo(Some(Channel::Pulse1));
+        apu.step();
+        assert!(apu.mix_level > 0.0);
+        apu.end_audio_frame();
+
+        let peak = |audio: &mut audio::AudioOutput| {
+            let mut out = vec![0.0; audio.samples_available()];
+            audio.read_samples(&mut out);
+            out[400..].iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+        };
+        // captures ignore the mixer settings
+        assert!(peak(apu.channel_audio_mut(Channel::Pulse1).unwrap()) > 0.05);
+        assert!(peak(apu.channel_audio_mut(Channel::Noise).unwrap()) == 0.0);
+        apu.set_channel_capture(false);
+        assert!(apu.channel_audio_mut(Channel::Pulse1).is_none());
+    }
+}
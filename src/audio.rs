@@ -0,0 +1,289 @@
+// turns the APU's channel levels into samples at the host's rate
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// taps of the band-limited step and the number of sub-sample positions
+// it is precomputed for
+const STEP_WIDTH: usize = 16;
+const STEP_PHASES: usize = 64;
+// cutoff of the step relative to the output Nyquist frequency, a little
+// below it so aliasing is damped before the output low-pass
+static STEP_CUTOFF: f64 = 0.9;
+
+// corner frequencies of the filters between the DAC and the audio jack
+static HIGH_PASS_HZ: [f32; 2] = [90.0, 440.0];
+static LOW_PASS_HZ: f32 = 14000.0;
+
+lazy_static! {
+    // windowed sinc impulses, one row per phase and normalised to a sum of
+    // one, adding a row and integrating gives a band-limited step
+    static ref STEP_KERNEL: Vec<[f32; STEP_WIDTH]> = {
+        let half = STEP_WIDTH as f64 / 2.0;
+        (0..=STEP_PHASES).map(|phase| {
+            let frac = phase as f64 / STEP_PHASES as f64;
+            let mut taps = [0.0; STEP_WIDTH];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let x = i as f64 - half + 0.5 - frac;
+                let sinc = if x == 0.0 {1.0} else {
+                    (PI * x * STEP_CUTOFF).sin() / (PI * x * STEP_CUTOFF)
+                };
+                let window = 0.5 + 0.5 * (PI * x / (half + 1.0)).cos();
+                *tap = (sinc * window) as f32;
+            }
+            let sum: f32 = taps.iter().sum();
+            taps.map(|tap| tap / sum)
+        }).collect()
+    };
+}
+
+// the 2A03's non-linear DAC, pulse levels are 0-15 and so are triangle
+// and noise, the DMC is 0-127. Levels may be fractional.
+pub fn mix(pulse1: f32, pulse2: f32, triangle: f32, noise: f32, dmc: f32) -> f32 {
+    let pulse = pulse1 + pulse2;
+    let pulse_out = if pulse > 0.0 {95.88 / (8128.0 / pulse + 100.0)} else {0.0};
+    let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+    let tnd_out = if tnd > 0.0 {159.79 / (1.0 / tnd + 100.0)} else {0.0};
+    pulse_out + tnd_out
+}
+
+// blip-buffer style resampler. Changes of the input level are added as
+// band-limited steps at their exact clock, end_frame then integrates
+// every output sample no later step can reach anymore.
+pub struct BlipBuf {
+    // output samples per input clock
+    factor: f64,
+    // position of clock 0 of the current frame, in output samples
+    offset: f64,
+    deltas: Vec<f32>,
+    level: f32
+}
+
+impl BlipBuf {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        BlipBuf {
+            factor: sample_rate as f64 / clock_rate,
+            offset: 0.0,
+            deltas: vec![0.0; STEP_WIDTH],
+            level: 0.0
+        }
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: u32) {
+        self.factor = sample_rate as f64 / clock_rate;
+    }
+
+    // the input changes by `delta` at `clock` of the current frame
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let pos = self.offset + clock as f64 * self.factor;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * STEP_PHASES as f64).round() as usize;
+        if self.deltas.len() < index + STEP_WIDTH {
+            self.deltas.resize(index + STEP_WIDTH, 0.0);
+        }
+        for (slot, tap) in self.deltas[index..].iter_mut().zip(STEP_KERNEL[phase].iter()) {
+            *slot += delta * tap;
+        }
+    }
+
+    // ends the frame after `clocks` input clocks, appending the finished
+    // samples to `out`. The output lags the input by STEP_WIDTH / 2 samples.
+    pub fn end_frame(&mut self, clocks: u32, out: &mut Vec<f32>) {
+        let end = self.offset + clocks as f64 * self.factor;
+        let count = end as usize;
+        if self.deltas.len() < count + STEP_WIDTH {
+            self.deltas.resize(count + STEP_WIDTH, 0.0);
+        }
+        for delta in self.deltas.drain(..count) {
+            self.level += delta;
+            out.push(self.level);
+        }
+        self.offset = end - count as f64;
+    }
+}
+
+// first order filter, either a high-pass or a low-pass
+struct OnePole {
+    high_pass: bool,
+    alpha: f32,
+    input: f32,
+    output: f32
+}
+
+impl OnePole {
+    fn new(high_pass: bool, cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        OnePole {
+            high_pass,
+            alpha: if high_pass {rc / (rc + dt)} else {dt / (rc + dt)},
+            input: 0.0,
+            output: 0.0
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.output = if self.high_pass {
+            self.alpha * (self.output + input - self.input)
+        } else {
+            self.output + self.alpha * (input - self.output)
+        };
+        self.input = input;
+        self.output
+    }
+}
+
+// a resampled and filtered stream waiting to be pulled by the host
+pub struct AudioOutput {
+    sample_rate: u32,
+    clock_rate: f64,
+    blip: BlipBuf,
+    filters: Vec<OnePole>,
+    pending: Vec<f32>,
+    samples: VecDeque<f32>,
+    // copy of everything produced while a recorder listens, independent
+    // of what the host pulls
+    tap: Option<Vec<f32>>
+}
+
+impl AudioOutput {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        AudioOutput {
+            sample_rate,
+            clock_rate,
+            blip: BlipBuf::new(clock_rate, sample_rate),
+            filters: AudioOutput::filter_chain(sample_rate),
+            pending: Vec::new(),
+            samples: VecDeque::new(),
+            tap: None
+        }
+    }
+
+    fn filter_chain(sample_rate: u32) -> Vec<OnePole> {
+        vec![
+            OnePole::new(true, HIGH_PASS_HZ[0], sample_rate),
+            OnePole::new(true, HIGH_PASS_HZ[1], sample_rate),
+            OnePole::new(false, LOW_PASS_HZ, sample_rate)
+        ]
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.blip.set_rates(self.clock_rate, sample_rate);
+        self.filters = AudioOutput::filter_chain(sample_rate);
+        self.samples.clear();
+    }
+
+    pub fn clock_rate(&self) -> f64 {
+        self.clock_rate
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: f64) {
+        self.clock_rate = clock_rate;
+        self.blip.set_rates(clock_rate, self.sample_rate);
+    }
+
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        self.blip.add_delta(clock, delta);
+    }
+
+    // filters the samples finished by the first `clocks` clocks. Samples
+    // nobody pulls are dropped once more than a second is queued.
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.blip.end_frame(clocks, &mut self.pending);
+        for sample in self.pending.drain(..) {
+            let filtered = self.filters.iter_mut().fold(sample, |s, filter| filter.process(s));
+            self.samples.push_back(filtered);
+            if let Some(tap) = &mut self.tap {
+                tap.push(filtered);
+            }
+        }
+        let excess = self.samples.len().saturating_sub(self.sample_rate as usize);
+        self.samples.drain(..excess);
+    }
+
+    // starts or stops copying new samples aside for take_tapped
+    pub fn set_tap(&mut self, enabled: bool) {
+        self.tap = if enabled {Some(Vec::new())} else {None};
+    }
+
+    // samples produced since the last call while the tap is on
+    pub fn take_tapped(&mut self) -> Vec<f32> {
+        self.tap.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn samples_available(&self) -> usize {
+        self.samples.len()
+    }
+
+    // moves up to out.len() samples into `out`, returning how many
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.samples.len());
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *slot = sample;
+        }
+        count
+    }
+
+    pub fn read_samples_i16(&mut self, out: &mut [i16]) -> usize {
+        let count = out.len().min(self.samples.len());
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *slot = to_i16(sample);
+        }
+        count
+    }
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixer_matches_reference_levels() {
+        assert_eq!(mix(0.0, 0.0, 0.0, 0.0, 0.0), 0.0);
+        // full scale pulses and the rest, from the formulas on the wiki
+        assert!((mix(15.0, 15.0, 0.0, 0.0, 0.0) - 0.2585).abs() < 0.0001);
+        assert!((mix(0.0, 0.0, 15.0, 15.0, 127.0) - 0.7415).abs() < 0.0001);
+        // the DAC saturates, doubling the input does not double the output
+        assert!(mix(30.0, 0.0, 0.0, 0.0, 0.0) < 2.0 * mix(15.0, 0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn blip_buffer_resamples_steps() {
+        let mut blip = BlipBuf::new(1_789_773.0, 44_100);
+        let mut out = Vec::new();
+        blip.add_delta(100, 0.5);
+        blip.end_frame(29_830, &mut out);
+        // 29830 clocks are 735 samples, the fraction carries over
+        assert_eq!(out.len(), 735);
+        assert!(out[0].abs() < 0.001);
+        assert!((out[100] - 0.5).abs() < 0.001);
+        blip.end_frame(29_830, &mut out);
+        assert_eq!(out.len(), 1470);
+        assert!((out[1469] - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn output_is_filtered_and_pulled() {
+        let mut audio = AudioOutput::new(1_789_773.0, 48_000);
+        audio.add_delta(0, 0.5);
+        audio.end_frame(29_830);
+        assert_eq!(audio.samples_available(), 800);
+        let mut out = [0.0; 600];
+        assert_eq!(audio.read_samples(&mut out), 600);
+        // the high-passes let the step through and then pull it to zero
+        assert!(out.iter().any(|s| *s > 0.3));
+        assert!(out[599].abs() < 0.05);
+        let mut rest = [0i16; 300];
+        assert_eq!(audio.read_samples_i16(&mut rest), 200);
+        assert_eq!(audio.samples_available(), 0);
+    }
+}
@@ -0,0 +1,376 @@
+use anyhow::{anyhow, Result};
+use crate::apu;
+use crate::cart;
+use crate::clock;
+use crate::controller;
+use crate::ppu;
+
+
+// the CPU sees memory and interrupt lines only through this trait
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> Result<u8>;
+    fn write(&mut self, addr: u16, data: u8) -> Result<()>;
+
+    // read without side effects, for tracing and disassembly. Buses that
+    // cannot do that return None and are shown as zero.
+    fn peek(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+    // (scanline, dot) reported in trace logs
+    fn ppu_position(&self) -> (u16, u16) {
+        (0, 0)
+    }
+
+    // returns true once for every NMI edge raised since the last poll
+    fn poll_nmi(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+    fn irq_line(&self) -> bool {
+        false
+    }
+    // page written to $4014 since the last poll, the CPU then halts and
+    // copies it to $2004 itself
+    fn poll_oam_dma(&mut self) -> Option<u8> {
+        None
+    }
+    // address of a DMC sample fetch, the CPU halts, reads it and hands
+    // the byte back through complete_dmc_dma
+    fn poll_dmc_dma(&mut self) -> Option<u16> {
+        None
+    }
+    fn complete_dmc_dma(&mut self, _data: u8) {}
+    // called once per CPU cycle for devices running off the CPU clock
+    fn tick(&mut self) {}
+}
+
+// the NES memory map, owning every device the CPU can reach
+pub struct SystemBus {
+    pub(crate) ram: Vec<u8>,
+    pub(crate) ppu: ppu::PPU,
+    pub(crate) apu: apu::Apu,
+    pub(crate) cart: cart::Cartridge,
+    pub(crate) controllers: [controller::Controller; 2],
+    pub(crate) clock: clock::MasterClock,
+    oam_dma_page: Option<u8>,
+    // while the CPU runs ahead of the PPU the bus advances the clock
+    // itself, catching the PPU up only before the CPU could observe it
+    catch_up: bool,
+    cpu_cycle_ended: bool
+}
+
+impl SystemBus {
+    pub fn new(cart: cart::Cartridge) -> Self {
+        SystemBus {
+            ram: vec![0; 2048],
+            ppu: ppu::PPU::new(),
+            apu: apu::Apu::new(),
+            cart,
+            controllers: [controller::Controller::new(), controller::Controller::new()],
+            clock: clock::MasterClock::new(clock::Region::NTSC),
+            oam_dma_page: None,
+            catch_up: false,
+            cpu_cycle_ended: false
+        }
+    }
+
+    pub fn set_region(&mut self, region: clock::Region) {
+        self.clock = clock::MasterClock::new(region);
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    // from here on every CPU cycle advances the clock, see end_catch_up
+    pub fn begin_catch_up(&mut self) {
+        self.catch_up = true;
+        self.cpu_cycle_ended = false;
+    }
+
+    // brings the PPU up to the next CPU cycle and hands the clock back
+    // to whoever calls MasterClock::advance
+    pub fn end_catch_up(&mut self) -> Result<()> {
+        self.catch_up = false;
+        self.start_cpu_cycle();
+        self.sync_ppu()
+    }
+
+    // the clock only moves past a CPU cycle once the next one starts, so
+    // an NMI poll right after a cycle still sees the PPU of that cycle
+    fn start_cpu_cycle(&mut self) {
+        if self.cpu_cycle_ended {
+            self.cpu_cycle_ended = false;
+            self.clock.advance_cpu(1);
+        }
+    }
+
+    // runs the dots due up to the current CPU cycle
+    fn sync_ppu(&mut self) -> Result<()> {
+        while self.clock.ppu_due() {
+            self.clock.advance_ppu();
+            self.ppu.step(&mut self.cart)?;
+        }
+        Ok(())
+    }
+
+    // PPU registers and OAM DMA are the only way the CPU sees the PPU
+    fn catch_up_for(&mut self, addr: u16) -> Result<()> {
+        if !self.catch_up {
+            return Ok(());
+        }
+        self.start_cpu_cycle();
+        if (0x2000..0x4000).contains(&addr) || addr == 0x4014 {
+            self.sync_ppu()?;
+        }
+        Ok(())
+    }
+
+    pub fn step_ppu(&mut self) -> Result<()> {
+        self.ppu.step(&mut self.cart)
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) -> Result<()> {
+        debug!("write_ram called, address: {:#06x}, data: {:#04x}", addr, data);
+        self.ram[addr as usize] = data;
+        Ok(())
+    }
+    fn write_cart(&mut self, addr: u16, data: u8) -> Result<()>{
+        debug!("write_cart called, address: {:#06x}, data: {:#04x}", addr, data);
+        self.cart.write_sram(addr, data)
+    }
+    fn write_joy1(&mut self, data: u8) -> Result<()> {
+        debug!("write_joy1 called, data: {:#04x}", data);
+        // the strobe line is shared by both ports
+        for controller in self.controllers.iter_mut() {
+            controller.write(data);
+        }
+        Ok(())
+    }
+    fn write_unused_addr(&mut self, addr: u16, data: u8) -> Result<()> {
+        debug!("write_unused_addr called, address: {:#06x}, data: {:#04x}", addr, data);
+        Ok(())
+    }
+    fn write_ppu_register(&mut self, addr: u16, data: u8) -> Result<()> {
+        debug!("write_ppu_register called, address: {:#06x}, data: {:#04x}", addr, data);
+        self.ppu.write_register(&mut self.cart, addr, data)
+    }
+    fn write_apu_register(&mut self, addr: u16, data: u8) -> Result<()> {
+        debug!("write_apu_register called, address: {:#06x}, data: {:#04x}", addr, data);
+        self.apu.write_register(addr, data)
+    }
+    fn write_oamdma_addr(&mut self, data: u8) -> Result<()> {
+        debug!("write_oamdma_addr called, data: {:#04x}", data);
+        self.oam_dma_page = Some(data);
+        Ok(())
+    }
+
+    fn read_ram(&mut self, addr: u16) -> Result<u8> {
+        debug!("read_ram called, address: {:#06x}", addr);
+        Ok(self.ram[addr as usize])
+    }
+    fn read_sram(&mut self, addr: u16) -> Result<u8> {
+        self.cart.read_sram(addr)
+    }
+    fn read_cart(&mut self, addr: u16) -> Result<u8> {
+        self.cart.read_prg(addr)
+    }
+    fn read_joy1(&mut self) -> Result<u8> {
+        debug!("read_joy1 called");
+        Ok(self.controllers[0].read())
+    }
+    fn read_joy2(&mut self) -> Result<u8> {
+        debug!("read_joy2 called");
+        Ok(self.controllers[1].read())
+    }
+    fn read_unused_addr(&mut self, addr: u16) -> Result<u8> {
+        debug!("read_unused_addr called, addr: {:#06x}", addr);
+        Ok(0)
+    }
+    fn read_ppu_register(&mut self, addr: u16) -> Result<u8> {
+        debug!("read_ppu_register called, addr: {:#06x}", addr);
+        self.ppu.read_register(&self.cart, addr)
+    }
+    fn read_apu_register(&mut self, addr: u16) -> Result<u8> {
+        debug!("read_apu_register called, addr: {:#06x}", addr);
+        // only $4015 is readable
+        if addr == 0x4015 {
+            return Ok(self.apu.read_status());
+        }
+        Ok(0)
+    }
+    fn read_oamdma_addr(&mut self) -> Result<u8> {
+        debug!("read_oamdma_addr called");
+        Ok(0)
+    }
+}
+
+impl Bus for SystemBus {
+    fn read(&mut self, addr: u16) -> Result<u8> {
+        self.catch_up_for(addr)?;
+        match addr {
+            addr if addr < 0x2000 => self.read_ram(addr & 0x07FF),
+            addr if addr < 0x4000 => self.read_ppu_register(addr & 0x2007),
+            addr if (addr < 0x4014 || addr == 0x4015) => self.read_apu_register(addr),
+            0x4014                => self.read_oamdma_addr(),
+            0x4016                => self.read_joy1(),
+            0x4017                => self.read_joy2(),
+            addr if addr < 0x6000 => self.read_unused_addr(addr),
+            addr if addr < 0x8000 => self.read_sram(addr),
+            addr => self.read_cart(addr)
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) -> Result<()> {
+        self.catch_up_for(addr)?;
+        match addr {
+            addr if addr < 0x2000 => self.write_ram(addr & 0x07FF, data),
+            addr if addr < 0x4000 => self.write_ppu_register(addr & 0x2007, data),
+            addr if (addr < 0x4014 || addr == 0x4015) => self.write_apu_register(addr, data),
+            0x4014                => self.write_oamdma_addr(data),
+            0x4016                => self.write_joy1(data),
+            0x4017                => self.write_apu_register(addr, data),
+            addr if addr < 0x6000 => self.write_unused_addr(addr, data),
+            addr if addr < 0x8000 => self.write_cart(addr, data),
+            addr => self.write_unused_addr(addr, data)
+        }
+    }
+
+    // I/O registers other than $4015 and the controllers read as zero
+    fn peek(&self, addr: u16) -> Option<u8> {
+        let data = match addr {
+            addr if addr < 0x2000 => self.ram[(addr & 0x07FF) as usize],
+            0x4015                => self.apu.peek_status(),
+            0x4016                => self.controllers[0].peek(),
+            0x4017                => self.controllers[1].peek(),
+            addr if addr < 0x6000 => 0,
+            addr if addr < 0x8000 => self.cart.read_sram(addr).unwrap_or(0),
+            addr => self.cart.read_prg(addr).unwrap_or(0)
+        };
+        Some(data)
+    }
+
+    // lags behind while the CPU runs ahead, see begin_catch_up
+    fn ppu_position(&self) -> (u16, u16) {
+        self.ppu.position()
+    }
+
+    // a PPU left behind only has to catch up once it could have reached
+    // the dot raising the NMI
+    fn poll_nmi(&mut self) -> Result<bool> {
+        if self.catch_up && self.clock.ppu_cycles_due() > self.ppu.dots_before_vblank() {
+            self.sync_ppu()?;
+        }
+        Ok(self.ppu.poll_nmi())
+    }
+
+    fn poll_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma_page.take()
+    }
+
+    fn tick(&mut self) {
+        if self.catch_up {
+            self.start_cpu_cycle();
+            self.cpu_cycle_ended = true;
+        }
+        self.apu.step();
+    }
+
+    fn irq_line(&self) -> bool {
+        self.apu.irq_line()
+    }
+
+    fn poll_dmc_dma(&mut self) -> Option<u16> {
+        self.apu.dmc_dma_request()
+    }
+
+    fn complete_dmc_dma(&mut self, data: u8) {
+        self.apu.complete_dmc_dma(data);
+    }
+}
+
+// 64 KiB of plain RAM for running the 6502 core outside of a NES
+pub struct FlatRam {
+    mem: Vec<u8>
+}
+
+impl FlatRam {
+    pub fn new() -> Self {
+        FlatRam {
+            mem: vec![0; 0x10000]
+        }
+    }
+
+    // copies `data` into memory starting at `addr`
+    pub fn load(&mut self, addr: u16, data: &[u8]) -> Result<()> {
+        let start = addr as usize;
+        if start + data.len() > self.mem.len() {
+            return Err(anyhow!("{} bytes do not fit at {:#06x}", data.len(), addr));
+        }
+        self.mem[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl Default for FlatRam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatRam {
+    fn read(&mut self, addr: u16) -> Result<u8> {
+        Ok(self.mem[addr as usize])
+    }
+
+    fn write(&mut self, addr: u16, data: u8) -> Result<()> {
+        self.mem[addr as usize] = data;
+        Ok(())
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        Some(self.mem[addr as usize])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{BUTTON_A, BUTTON_START};
+
+    #[test]
+    fn ram_is_mirrored() {
+        let mut bus = SystemBus::new(cart::Cartridge::new());
+        bus.write(0x0801, 0x42).unwrap();
+        assert_eq!(bus.read(0x1801).unwrap(), 0x42);
+        assert_eq!(bus.peek(0x0001), Some(0x42));
+    }
+
+    #[test]
+    fn prg_ram_needs_no_battery() {
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 0x01, 0x01, 0x00, 0x00];
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let mut cart = cart::Cartridge::new();
+        cart.load_from_bytes(&rom).unwrap();
+        assert!(!cart.has_battery());
+        let mut bus = SystemBus::new(cart);
+        bus.write(0x6123, 0x42).unwrap();
+        assert_eq!(bus.read(0x6123).unwrap(), 0x42);
+        assert_eq!(bus.peek(0x6123), Some(0x42));
+    }
+
+    #[test]
+    fn empty_cartridge_reads_fail_without_panicking() {
+        let mut bus = SystemBus::new(cart::Cartridge::new());
+        assert!(bus.read(0x8000).is_err());
+        assert_eq!(bus.peek(0xfffc), Some(0));
+    }
+
+    #[test]
+    fn controller_shifts_after_strobe() {
+        let mut bus = SystemBus::new(cart::Cartridge::new());
+        bus.controllers[0].set_buttons(BUTTON_A | BUTTON_START);
+        bus.write(0x4016, 1).unwrap();
+        bus.write(0x4016, 0).unwrap();
+        let bits: Vec<u8> = (0..9).map(|_| bus.read(0x4016).unwrap() & 0x01).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 0, 1]);
+    }
+}
@@ -1,4 +1,5 @@
 use std::fs::File;
+use std::io::Cursor;
 use std::io::SeekFrom;
 use std::io::Read;
 use std::io::Seek;
@@ -7,7 +8,9 @@ use crate::utils;
 use anyhow::{anyhow, Result};
 
 
-static SRAM_FLAG: u8 = 0x02;
+static BATTERY_FLAG: u8 = 0x02;
+// flags 8-10 are parsed for completeness, nothing reads them yet
+#[allow(dead_code)]
 struct RomHeader {
     prg: u8,
     chr: u8,
@@ -18,20 +21,29 @@ struct RomHeader {
     flag10: u8
 }
 
-#[derive(Clone, Copy, Debug)]
+// nametable arrangement, SINGLEA and SINGLEB map all four nametables onto
+// the first or second KiB of VRAM
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MirrorType {
     HORIZONTAL,
     VERTICAL,
-    NONE
+    SINGLEA,
+    SINGLEB,
+    FOURSCREEN
 }
 
 pub struct Cartridge {
     mapper_code: u8,
     prg: Vec<u8>,
     chr: Vec<u8>,
-    sram: Option<Vec<u8>>,
+    // boards without CHR ROM carry 8 KiB of CHR RAM instead
+    chr_ram: bool,
+    // PRG-RAM at $6000-$7FFF, the battery flag only says whether it keeps
+    // its contents while switched off
+    sram: Vec<u8>,
     prg_mirror: bool,
-    has_sram: bool,
+    battery: bool,
     mirror_type: MirrorType
 }
 
@@ -42,14 +54,23 @@ impl Cartridge {
             mapper_code: 0,
             prg: Vec::new(),
             chr: Vec::new(),
-            sram: None,
+            chr_ram: false,
+            sram: vec![0; 8 * 1024],
             prg_mirror: false,
-            has_sram: false,
-            mirror_type: MirrorType::NONE
+            battery: false,
+            mirror_type: MirrorType::HORIZONTAL
         }
     }
     pub fn load_from_file(&mut self, path: &str) ->Result<()> {
         let mut rom_file = File::open(path)?;
+        self.load(&mut rom_file)
+    }
+
+    pub fn load_from_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.load(&mut Cursor::new(data))
+    }
+
+    fn load<R: Read + Seek>(&mut self, rom_file: &mut R) -> Result<()> {
         //parse header
         let mut buffer = [0; 16];
         let n = rom_file.read(&mut buffer)?;
@@ -77,46 +98,54 @@ impl Cartridge {
             return Err(anyhow!("invalied prg unit count: {}", rom_header.prg));
         }
         debug!("PRG unit size: {}", rom_header.prg);
-        let prg_mirror = if rom_header.prg == 1 {true} else {false};
+        let prg_mirror = rom_header.prg == 1;
         let mut prg_buffer = vec![0u8; 16 * 1024 * rom_header.prg as usize];
         rom_file.read_exact(&mut prg_buffer)?;
         //read chr data
-        if rom_header.chr == 0 {
-            return Err(anyhow!("no CHR data in rom file"));
-        }
         debug!("CHR unit size: {}", rom_header.chr);
-        let mut chr_buffer = vec![0u8; 8 * 1024 * rom_header.chr as usize];
-        rom_file.read_exact(&mut chr_buffer)?;
+        let chr_ram = rom_header.chr == 0;
+        let mut chr_buffer = vec![0u8; 8 * 1024 * rom_header.chr.max(1) as usize];
+        if !chr_ram {
+            rom_file.read_exact(&mut chr_buffer)?;
+        }
         //nametable mirror type
+        // four-screen boards ignore the mirroring bit
         let mirror_type = match rom_header.flag6 & 0x09 {
-            8 =>  Ok(MirrorType::NONE),
-            0 =>  Ok(MirrorType::HORIZONTAL),
-            1 =>  Ok(MirrorType::VERTICAL),
-            _ => Err(anyhow!("unknown mirror type, flag6: {:#06x}", rom_header.flag6))
-        }?;
+            0 => MirrorType::HORIZONTAL,
+            1 => MirrorType::VERTICAL,
+            _ => MirrorType::FOURSCREEN
+        };
         self.mapper_code = mapper_code;
         self.prg = prg_buffer;
         self.chr = chr_buffer;
+        self.chr_ram = chr_ram;
         self.prg_mirror = prg_mirror;
-        self.has_sram = utils::binaryBoolAnd(rom_header.flag6, SRAM_FLAG);
+        self.battery = utils::binaryBoolAnd(rom_header.flag6, BATTERY_FLAG);
         self.mirror_type = mirror_type;
-        if self.has_sram {
-            self.sram = Some(Vec::with_capacity(2*1024));
-        }
+        self.sram = vec![0; 8 * 1024];
         Ok(())
     }
 
     fn nrom_mapper_read_prg(&self, addr: u16) -> Result<u8> {
-        if self.prg_mirror {
-            let idx = (addr - 0x8000) & 0x3fff;
-            Ok(self.prg[idx as usize])
-        }else {
-            let idx = addr - 0x8000;
-            Ok(self.prg[idx as usize])
-        }
+        let idx = if self.prg_mirror {
+            (addr - 0x8000) & 0x3fff
+        } else {
+            addr - 0x8000
+        };
+        self.prg.get(idx as usize).copied()
+            .ok_or_else(|| anyhow!("no PRG ROM loaded, {:#06x}", addr))
     }
     fn nrom_mapper_read_chr(&self, addr: u16) -> Result<u8> {
-        Ok(self.chr[addr as usize])
+        self.chr.get(addr as usize).copied()
+            .ok_or_else(|| anyhow!("no CHR loaded, {:#06x}", addr))
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        !self.prg.is_empty()
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery
     }
     pub fn read_prg(&self, addr: u16) -> Result<u8> {
         match self.mapper_code {
@@ -126,17 +155,11 @@ impl Cartridge {
     }
 
     pub fn read_sram(&self, addr: u16) -> Result<u8> {
-        if !self.has_sram {
-            return Err(anyhow!("address not readable, {:#06x}", addr));
-        }
-        Ok(self.sram.unwrap()[addr as usize - 0x6000])
+        Ok(self.sram[(addr as usize - 0x6000) & 0x1fff])
     }
 
-    pub fn write_sram(&self, addr: u16, data: u8) -> Result<()>{
-        if !self.has_sram {
-            return Err(anyhow!("address not writeable, {:#06x}", addr));
-        }
-        self.sram.unwrap()[addr as usize - 0x6000] = data;
+    pub fn write_sram(&mut self, addr: u16, data: u8) -> Result<()>{
+        self.sram[(addr as usize - 0x6000) & 0x1fff] = data;
         Ok(())
     }
 
@@ -147,8 +170,25 @@ impl Cartridge {
         }
     }
 
+    pub fn write_chr(&mut self, addr: u16, data: u8) -> Result<()> {
+        if !self.chr_ram {
+            debug!("write to chr rom ignored, address: {:#06x}, data: {:#04x}", addr, data);
+            return Ok(());
+        }
+        match self.chr.get_mut(addr as usize) {
+            Some(slot) => *slot = data,
+            None => return Err(anyhow!("no CHR loaded, {:#06x}", addr))
+        }
+        Ok(())
+    }
+
     pub fn get_mirror_type(&self) -> MirrorType {
         self.mirror_type
     }
+
+    // mappers with mirroring control switch the arrangement at runtime
+    pub fn set_mirror_type(&mut self, mirror_type: MirrorType) {
+        self.mirror_type = mirror_type;
+    }
 }
 
@@ -0,0 +1,169 @@
+// console timings, every component clock is derived from the master clock
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Region {
+    NTSC,
+    PAL,
+    Dendy
+}
+
+impl Region {
+    pub fn master_clock_hz(self) -> u64 {
+        match self {
+            Region::NTSC => 21_477_272,
+            Region::PAL | Region::Dendy => 26_601_712
+        }
+    }
+
+    pub fn cpu_divider(self) -> u64 {
+        match self {
+            Region::NTSC => 12,
+            Region::PAL => 16,
+            Region::Dendy => 15
+        }
+    }
+
+    pub fn ppu_divider(self) -> u64 {
+        match self {
+            Region::NTSC => 4,
+            Region::PAL | Region::Dendy => 5
+        }
+    }
+
+    pub fn cpu_clock_hz(self) -> f64 {
+        self.master_clock_hz() as f64 / self.cpu_divider() as f64
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Component {
+    CPU,
+    PPU
+}
+
+// hands out CPU and PPU cycles in master clock order, when both fall on
+// the same master cycle the PPU goes first
+pub struct MasterClock {
+    region: Region,
+    cycles: u64,
+    cpu_next: u64,
+    ppu_next: u64
+}
+
+impl MasterClock {
+    pub fn new(region: Region) -> Self {
+        MasterClock {
+            region,
+            cycles: 0,
+            cpu_next: 0,
+            ppu_next: 0
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // master cycles elapsed
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn reset(&mut self) {
+        self.cycles = 0;
+        self.cpu_next = 0;
+        self.ppu_next = 0;
+    }
+
+    // the component owning the next cycle, advancing the clock to it
+    pub fn advance(&mut self) -> Component {
+        if self.ppu_next <= self.cpu_next {
+            self.cycles = self.ppu_next;
+            self.ppu_next += self.region.ppu_divider();
+            Component::PPU
+        } else {
+            self.cycles = self.cpu_next;
+            self.cpu_next += self.region.cpu_divider();
+            Component::CPU
+        }
+    }
+
+    // true when the PPU owns the next cycle
+    pub fn ppu_due(&self) -> bool {
+        self.ppu_next <= self.cpu_next
+    }
+
+    pub fn advance_ppu(&mut self) {
+        self.cycles = self.ppu_next;
+        self.ppu_next += self.region.ppu_divider();
+    }
+
+    // lets the CPU run `cpu_cycles` ahead in one go; the PPU has to be
+    // caught up with advance_ppu while ppu_due before anything observes it
+    pub fn advance_cpu(&mut self, cpu_cycles: u64) {
+        self.cycles = self.cpu_next;
+        self.cpu_next += cpu_cycles * self.region.cpu_divider();
+    }
+
+    // PPU cycles that are due, running them all makes ppu_due false
+    pub fn ppu_cycles_due(&self) -> u64 {
+        if self.ppu_next > self.cpu_next {
+            return 0;
+        }
+        (self.cpu_next - self.ppu_next) / self.region.ppu_divider() + 1
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(region: Region, master_cycles: u64) -> (u64, u64) {
+        let mut clock = MasterClock::new(region);
+        let (mut cpu, mut ppu) = (0, 0);
+        loop {
+            let component = clock.advance();
+            if clock.cycles() >= master_cycles {
+                return (cpu, ppu);
+            }
+            match component {
+                Component::CPU => cpu += 1,
+                Component::PPU => ppu += 1
+            }
+        }
+    }
+
+    #[test]
+    fn component_ratios_follow_region() {
+        assert_eq!(count(Region::NTSC, 12 * 1000), (1000, 3000));
+        assert_eq!(count(Region::PAL, 16 * 5 * 100), (500, 1600));
+        assert_eq!(count(Region::Dendy, 15 * 1000), (1000, 3000));
+    }
+
+    #[test]
+    fn catch_up_matches_lockstep() {
+        let mut lockstep = MasterClock::new(Region::NTSC);
+        let mut lockstep_dots = 0;
+        let mut cpu_cycles = 0;
+        while cpu_cycles <= 7 {
+            match lockstep.advance() {
+                Component::CPU => cpu_cycles += 1,
+                Component::PPU => lockstep_dots += 1
+            }
+        }
+
+        let mut clock = MasterClock::new(Region::NTSC);
+        let mut dots = 0;
+        while clock.ppu_due() {
+            clock.advance_ppu();
+            dots += 1;
+        }
+        clock.advance_cpu(7);
+        while clock.ppu_due() {
+            clock.advance_ppu();
+            dots += 1;
+        }
+        // both have run the dots up to and including the 8th CPU cycle
+        assert_eq!(dots, lockstep_dots);
+    }
+}
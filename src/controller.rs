@@ -0,0 +1,54 @@
+// standard joypad, buttons are reported in the order
+// A, B, Select, Start, Up, Down, Left, Right
+pub static BUTTON_A: u8 = 0x01;
+pub static BUTTON_B: u8 = 0x02;
+pub static BUTTON_SELECT: u8 = 0x04;
+pub static BUTTON_START: u8 = 0x08;
+pub static BUTTON_UP: u8 = 0x10;
+pub static BUTTON_DOWN: u8 = 0x20;
+pub static BUTTON_LEFT: u8 = 0x40;
+pub static BUTTON_RIGHT: u8 = 0x80;
+
+#[derive(Default)]
+pub struct Controller {
+    buttons: u8,
+    shift: u8,
+    strobe: bool
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Controller::default()
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+
+    // bit 0 of $4016 reloads the shift register while it is held high
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    // after all eight buttons have been read the register returns 1s
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return 0x40 | (self.buttons & 0x01);
+        }
+        let bit = self.shift & 0x01;
+        self.shift = (self.shift >> 1) | 0x80;
+        0x40 | bit
+    }
+
+    // the next bit a read would return, without shifting
+    pub fn peek(&self) -> u8 {
+        let bits = if self.strobe {self.buttons} else {self.shift};
+        0x40 | (bits & 0x01)
+    }
+}
@@ -1,12 +1,11 @@
 use std::collections::HashMap;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::io::Write;
 use anyhow::{anyhow, Result};
-use crate::cart;
-use crate::ppu;
+use crate::bus::{Bus, SystemBus};
+use crate::disasm;
 
 
-pub struct CPU {
+pub struct CPU<B: Bus = SystemBus> {
 
     //registers
     r_pc: u16,
@@ -18,18 +17,42 @@ pub struct CPU {
 
     cycles: usize,
     skip_cycles: usize,
+    page_crossed: bool,
+    extra_cycles: usize,
+    // memory instructions resolve their operand when fetched and access
+    // memory on their last cycle, these hold the address until then
+    deferred: Option<Op>,
+    resolved: Option<(u16, bool)>,
+    // write cycles ending the instruction in progress, a DMC DMA halt
+    // waits for them
+    trailing_writes: usize,
+    oam_dma_end: usize,
+    // DMC sample fetch: the address, cycles to run before the halt and
+    // the halted cycles, the last of which reads the sample
+    dmc_fetch: Option<u16>,
+    dmc_wait: usize,
+    dmc_stall: usize,
+    dmc_halted: bool,
+    illegal_opcodes: bool,
+    decimal_mode: bool,
 
-    frame_ready: bool,
+    // interrupt state
+    nmi_pending: bool,
+    irq_line: bool,
+    irq_inhibit: bool,
+    hijack_until: Option<usize>,
+    hijacked: bool,
 
-    ram: Vec<u8>,
-    cart: Rc<RefCell<cart::Cartridge>>,
-    ppu: Rc<RefCell<ppu::PPU>>
+    tracer: Option<Box<dyn Write>>,
+
+
+    bus: B
 
 }
 
 
-impl CPU {
-    pub fn new(cart: Rc<RefCell<cart::Cartridge>>, ppu: Rc<RefCell<ppu::PPU>>) -> Self {
+impl<B: Bus> CPU<B> {
+    pub fn new(bus: B) -> Self {
         CPU {
             r_pc: 0,
             r_a: 0,
@@ -39,21 +62,98 @@ impl CPU {
             r_st: 0,
             cycles: 0,
             skip_cycles: 0,
-            frame_ready: false,
-            ram: Vec::with_capacity(2048),
-            cart: cart,
-            ppu: ppu
+            page_crossed: false,
+            extra_cycles: 0,
+            deferred: None,
+            resolved: None,
+            trailing_writes: 0,
+            oam_dma_end: 0,
+            dmc_fetch: None,
+            dmc_wait: 0,
+            dmc_stall: 0,
+            dmc_halted: false,
+            illegal_opcodes: true,
+            decimal_mode: false,
+            nmi_pending: false,
+            irq_line: false,
+            irq_inhibit: true,
+            hijack_until: None,
+            hijacked: false,
+            tracer: None,
+            bus
         }
     }
 
-    pub fn interrupt(tp: InteruptType) {
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.r_pc
+    }
+    pub fn set_pc(&mut self, pc: u16) {
+        self.r_pc = pc;
+    }
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+    // true when the next cycle fetches an opcode or starts an interrupt
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.skip_cycles == 0 && self.dmc_fetch.is_none()
+    }
+
+    // NMI is edge-triggered and latched until serviced. An NMI raised during
+    // the first four cycles of a BRK or IRQ sequence takes over its vector.
+    pub fn interrupt(&mut self, tp: InteruptType) {
+        match tp {
+            InteruptType::NMI => match self.hijack_until {
+                Some(until) if self.cycles <= until => {
+                    self.hijack_until = None;
+                    self.hijacked = true;
+                },
+                _ => self.nmi_pending = true
+            },
+            InteruptType::IRQ => self.irq_line = true,
+            InteruptType::BRK => debug!("BRK can only be raised by the instruction")
+        }
+    }
 
+    // IRQ is level-triggered, it stays asserted until the source releases it
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
     }
-    pub fn is_frame_ready(&self) -> bool {
-        self.frame_ready
+    // unofficial opcodes are executed by default; disabling them turns
+    // each one into an error, which is useful for strict homebrew testing
+    pub fn set_illegal_opcodes(&mut self, enabled: bool) {
+        self.illegal_opcodes = enabled;
     }
-    pub fn init(&mut self) {
-        self.reset();
+    // the 2A03 ignores the D flag; enabling this gives NMOS 6502 BCD
+    // arithmetic for non-NES use such as Klaus Dormann's functional test
+    pub fn set_decimal_mode(&mut self, enabled: bool) {
+        self.decimal_mode = enabled;
+    }
+    // writes one nestest.log style line per executed instruction
+    pub fn set_tracer(&mut self, out: Box<dyn Write>) {
+        self.tracer = Some(out);
+    }
+    pub fn clear_tracer(&mut self) {
+        self.tracer = None;
+    }
+    // jumps to the subroutine at `addr` with A and X loaded, as a JSR
+    // placed right before `return_to` would. Drives code that has no
+    // reset vector of its own, such as NSF INIT and PLAY routines.
+    pub fn call(&mut self, addr: u16, a: u8, x: u8, return_to: u16) -> Result<()> {
+        self.r_a = a;
+        self.r_x = x;
+        self.push_address(return_to.wrapping_sub(1))?;
+        self.r_pc = addr;
+        Ok(())
+    }
+    pub fn init(&mut self) -> Result<()> {
+        self.reset()
     }
     pub fn reset(&mut self) -> Result<()> {
 
@@ -65,160 +165,980 @@ impl CPU {
         //to be confirmed
         self.r_sp = STACK_START;
 
+        // the reset sequence takes as long as an interrupt
         self.cycles = 0;
-        self.skip_cycles = 0;
+        self.skip_cycles = INTERRUPT_CYCLES;
+        self.deferred = None;
+        self.resolved = None;
+        self.dmc_fetch = None;
+        self.nmi_pending = false;
+        self.irq_inhibit = true;
+        self.hijack_until = None;
+        self.hijacked = false;
         Ok(())
     }
     
-    fn read_address(&self, addr: u16) -> Result<u16> {
+    fn read_address(&mut self, addr: u16) -> Result<u16> {
         let addrl: u16 = self.read(addr)? as u16;
-        let addrh: u16 = self.read(addr+ 1)? as u16;
+        let addrh: u16 = self.read(addr.wrapping_add(1))? as u16;
         Ok((addrh << 8) | addrl)
     }
 
     pub fn step(&mut self) -> Result<()>{
         self.cycles += 1;
+        self.run_cycle()?;
+        self.bus.tick();
+        if self.dmc_fetch.is_none() {
+            if let Some(addr) = self.bus.poll_dmc_dma() {
+                self.dmc_dma(addr);
+            }
+        }
+        // the NMI line is sampled after this cycle's bus access, so a
+        // $2002 read racing the vblank flag can still suppress it
+        if self.bus.poll_nmi()? {
+            self.interrupt(InteruptType::NMI);
+        }
+        Ok(())
+    }
+
+    fn run_cycle(&mut self) -> Result<()> {
+        if let Some(addr) = self.dmc_fetch {
+            if self.dmc_wait > 0 {
+                self.dmc_wait -= 1;
+            } else {
+                return self.dmc_halt_cycle(addr);
+            }
+        }
         if self.skip_cycles > 0 {
             self.skip_cycles -= 1;
+            if self.skip_cycles == 0 {
+                if let Some(op) = self.deferred.take() {
+                    self.execute(&op)?;
+                    self.poll_oam_dma(self.cycles + 1)?;
+                }
+            }
+            return Ok(());
+        }
+        self.hijack_until = None;
+        if self.hijacked {
+            self.hijacked = false;
+            self.r_pc = self.read_address(NMI_VECTOR)?;
+        }
+
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.service_interrupt(InteruptType::NMI)?;
+            self.start_sequence(INTERRUPT_CYCLES, 0);
+            return Ok(());
+        }
+        if (self.irq_line || self.bus.irq_line()) && !self.irq_inhibit {
+            self.service_interrupt(InteruptType::IRQ)?;
+            self.start_sequence(INTERRUPT_CYCLES, 0);
             return Ok(());
         }
 
         let opcode: u8 = self.read(self.r_pc)?;
-        self.r_pc += 1;
+        let op = match OP_MAP.get(&opcode) {
+            Some(op) if self.illegal_opcodes || !op.0.is_unofficial(opcode) => op,
+            Some(_) => return Err(anyhow!("unofficial opcode {:#04x} at {:#06x}", opcode, self.r_pc)),
+            None => return Err(anyhow!("invalid opcode: {:#04x}", opcode))
+        };
+        if self.tracer.is_some() {
+            let line = self.trace_line(op);
+            if let Some(tracer) = self.tracer.as_mut() {
+                writeln!(tracer, "{}", line)?;
+            }
+        }
+        self.r_pc = self.r_pc.wrapping_add(1);
 
-        if !OP_MAP.contains_key(&opcode) {
-            return Err(anyhow!("invalid opcode: {}", opcode));
+        debug!("executing {:?}", op.0);
+        self.page_crossed = false;
+        self.extra_cycles = 0;
+        if defers_access(op) {
+            // the operand bytes and pointers are read now, the final read
+            // or write lands on the instruction's real last cycle
+            let (addr, crossed) = self.resolve_address(op.1)?;
+            self.resolved = Some((addr, crossed));
+            self.deferred = Some(*op);
+            let op_cycles = if op.4 && crossed {op.3 + 1} else {op.3};
+            self.start_sequence(op_cycles, trailing_writes(op));
+            return Ok(());
+        }
+        let prev_inhibit = self.get_flag(STATUS_I);
+        self.execute(op)?;
+        // CLI, SEI and PLP change I after the interrupt poll, so the old
+        // value decides whether an IRQ fires before the next instruction
+        self.irq_inhibit = match op.0 {
+            Instructions::CLI | Instructions::SEI | Instructions::PLP => prev_inhibit,
+            _ => self.get_flag(STATUS_I)
+        };
+
+        // this call already accounts for the first cycle of the instruction
+        let mut op_cycles = op.3 + self.extra_cycles;
+        if op.4 && self.page_crossed {
+            op_cycles += 1;
         }
+        self.start_sequence(op_cycles, trailing_writes(op));
+        Ok(())
+    }
 
-        let op = OP_MAP.get(&opcode).unwrap();
-        debug!("executin {:?}", op.0);
+    // a $4014 write halts the CPU from cycle `start` on
+    fn poll_oam_dma(&mut self, start: usize) -> Result<()> {
+        if let Some(page) = self.bus.poll_oam_dma() {
+            let stall = self.oam_dma(page, start)?;
+            // a DMC fetch already waiting goes first
+            self.oam_dma_end = self.cycles + self.dmc_stall + stall;
+            self.start_sequence(stall + 1, 0);
+        }
+        Ok(())
+    }
+
+    // the current cycle is the first of `cycles`, the last `writes` of
+    // which are write cycles
+    fn start_sequence(&mut self, cycles: usize, writes: usize) {
+        self.trailing_writes = writes;
+        self.skip_cycles = cycles - 1;
+    }
+
+    // schedules the halt for a DMC sample fetch requested during the
+    // cycle just run. The halt only takes effect on a read cycle, so
+    // pending writes finish first: 4 cycles are lost when the next cycle
+    // reads, 3 or 2 when one or two writes come first, and 1 to 3 when it
+    // overlaps OAM DMA.
+    fn dmc_dma(&mut self, addr: u16) {
+        let (wait, stall) = if self.cycles <= self.oam_dma_end {
+            let stall = match self.oam_dma_end - self.cycles {
+                0 => 3,
+                1 => 1,
+                _ => 2
+            };
+            self.oam_dma_end += stall;
+            (0, stall)
+        } else {
+            // the instruction's last `trailing_writes` cycles write
+            let remaining = self.skip_cycles;
+            if remaining > 0 && remaining <= self.trailing_writes {
+                (remaining, 4 - remaining)
+            } else {
+                (0, 4)
+            }
+        };
+        self.dmc_fetch = Some(addr);
+        self.dmc_wait = wait;
+        self.dmc_stall = stall;
+        self.dmc_halted = false;
+    }
+
+    fn dmc_halt_cycle(&mut self, addr: u16) -> Result<()> {
+        if !self.dmc_halted {
+            self.dmc_halted = true;
+            // halted on the final read of an instruction the CPU repeats
+            // it, clocking the controller shift register or the PPUDATA
+            // address a second time
+            let final_read = self.skip_cycles == 1 && self.trailing_writes == 0 && self.deferred.is_some();
+            match self.resolved {
+                Some((target, _)) if final_read && (target & 0xE007 == 0x2007 ||
+                    target == 0x4016 || target == 0x4017) => {
+                    self.read(target)?;
+                },
+                _ => {}
+            }
+        }
+        self.dmc_stall -= 1;
+        if self.dmc_stall == 0 {
+            self.dmc_fetch = None;
+            let data = self.read(addr)?;
+            self.bus.complete_dmc_dma(data);
+        }
+        Ok(())
+    }
+
+    // runs until the next instruction boundary, returning the cycles taken
+    pub fn step_instruction(&mut self) -> Result<usize> {
+        let start = self.cycles;
+        self.step()?;
+        while !self.at_instruction_boundary() {
+            self.step()?;
+        }
+        Ok(self.cycles - start)
+    }
+
+    // copies a page to OAM through $2004, returning the cycles the CPU is
+    // halted for. One cycle waits for the write to finish and another one
+    // aligns the transfer when `start` is odd, then reads and writes
+    // alternate for 512 cycles.
+    fn oam_dma(&mut self, page: u8, start: usize) -> Result<usize> {
+        let base = (page as u16) << 8;
+        for offset in 0..256 {
+            let data = self.read(base | offset)?;
+            self.write(0x2004, data)?;
+        }
+        Ok(513 + (start & 0x01))
+    }
+
+    fn execute(&mut self, op: &Op) -> Result<()> {
         match op.0 {
             Instructions::ORA => self.exe_ora(op),
-            _ => self.just_for_test(op)
+            Instructions::AND => self.exe_and(op),
+            Instructions::EOR => self.exe_eor(op),
+            Instructions::ADC => self.exe_adc(op),
+            Instructions::SBC => self.exe_sbc(op),
+            Instructions::LDA => self.exe_lda(op),
+            Instructions::STA => self.exe_sta(op),
+            Instructions::LDX => self.exe_ldx(op),
+            Instructions::STX => self.exe_stx(op),
+            Instructions::LDY => self.exe_ldy(op),
+            Instructions::STY => self.exe_sty(op),
+            Instructions::CMP => self.exe_compare(op, self.r_a),
+            Instructions::CPX => self.exe_compare(op, self.r_x),
+            Instructions::CPY => self.exe_compare(op, self.r_y),
+            Instructions::DEC => self.exe_dec(op),
+            Instructions::INC => self.exe_inc(op),
+            Instructions::DEX => self.exe_dex(),
+            Instructions::DEY => self.exe_dey(),
+            Instructions::INX => self.exe_inx(),
+            Instructions::INY => self.exe_iny(),
+            Instructions::ASL => self.exe_asl(op),
+            Instructions::ROL => self.exe_rol(op),
+            Instructions::LSR => self.exe_lsr(op),
+            Instructions::ROR => self.exe_ror(op),
+            Instructions::TAX => self.exe_tax(),
+            Instructions::TXA => self.exe_txa(),
+            Instructions::TAY => self.exe_tay(),
+            Instructions::TYA => self.exe_tya(),
+            Instructions::TSX => self.exe_tsx(),
+            Instructions::TXS => self.exe_txs(),
+            Instructions::PLA => self.exe_pla(),
+            Instructions::PHA => self.exe_pha(),
+            Instructions::PLP => self.exe_plp(),
+            Instructions::PHP => self.exe_php(),
+            Instructions::BPL => self.exe_branch(op, !self.get_flag(STATUS_N)),
+            Instructions::BMI => self.exe_branch(op, self.get_flag(STATUS_N)),
+            Instructions::BVC => self.exe_branch(op, !self.get_flag(STATUS_V)),
+            Instructions::BVS => self.exe_branch(op, self.get_flag(STATUS_V)),
+            Instructions::BCC => self.exe_branch(op, !self.get_flag(STATUS_C)),
+            Instructions::BCS => self.exe_branch(op, self.get_flag(STATUS_C)),
+            Instructions::BNE => self.exe_branch(op, !self.get_flag(STATUS_Z)),
+            Instructions::BEQ => self.exe_branch(op, self.get_flag(STATUS_Z)),
+            Instructions::CLC => self.exe_flag(STATUS_C, false),
+            Instructions::SEC => self.exe_flag(STATUS_C, true),
+            Instructions::CLD => self.exe_flag(STATUS_D, false),
+            Instructions::SED => self.exe_flag(STATUS_D, true),
+            Instructions::CLI => self.exe_flag(STATUS_I, false),
+            Instructions::SEI => self.exe_flag(STATUS_I, true),
+            Instructions::CLV => self.exe_flag(STATUS_V, false),
+            Instructions::JSR => self.exe_jsr(op),
+            Instructions::JMP => self.exe_jmp(op),
+            Instructions::RTS => self.exe_rts(),
+            Instructions::RTI => self.exe_rti(),
+            Instructions::BIT => self.exe_bit(op),
+            Instructions::BRK => self.exe_brk(),
+            Instructions::NOP => self.exe_nop(op),
+            Instructions::SLO => self.exe_slo(op),
+            Instructions::RLA => self.exe_rla(op),
+            Instructions::RRA => self.exe_rra(op),
+            Instructions::SRE => self.exe_sre(op),
+            Instructions::SAX => self.exe_sax(op),
+            Instructions::LAX => self.exe_lax(op),
+            Instructions::DCP => self.exe_dcp(op),
+            Instructions::ISC => self.exe_isc(op),
+            Instructions::ANC => self.exe_anc(op),
+            Instructions::ALR => self.exe_alr(op),
+            Instructions::ARR => self.exe_arr(op),
+            Instructions::AXS => self.exe_axs(op)
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) -> Result<()> {
+        self.bus.write(addr, data)
+    }
+
+    fn read(&mut self, addr: u16) -> Result<u8> {
+        self.bus.read(addr)
+    }
+
+    // side-effect free read used for tracing, buses without peek read as zero
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr).unwrap_or(0)
+    }
+
+    fn peek_zp_address(&self, ptr: u8) -> u16 {
+        (self.peek(ptr.wrapping_add(1) as u16) as u16) << 8 | self.peek(ptr as u16) as u16
+    }
+
+    pub fn disassemble(&self, start: u16, end: u16) -> Vec<disasm::Instruction> {
+        disasm::disassemble_range(|addr| self.peek(addr), start, end)
+    }
+
+    // Nintendulator/nestest.log line for the instruction at r_pc, the cycle
+    // count is the one before the instruction started
+    fn trace_line(&self, op: &Op) -> String {
+        let ins = disasm::decode(|addr| self.peek(addr), self.r_pc);
+        let prefix = if ins.unofficial {'*'} else {' '};
+        let asm = format!("{:04X}  {:<8} {}{} {}{}", ins.addr, ins.hex_bytes(), prefix,
+            trace_mnemonic(op.0), ins.operand, self.trace_annotation(op, &ins.bytes));
+        let (scanline, dot) = self.bus.ppu_position();
+        format!("{:<47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            asm.trim_end(), self.r_a, self.r_x, self.r_y, self.r_st, self.r_sp,
+            scanline, dot, self.cycles - 1)
+    }
+
+    // effective address and memory contents shown after the operand
+    fn trace_annotation(&self, op: &Op, bytes: &[u8]) -> String {
+        let lo = bytes.get(1).copied().unwrap_or(0);
+        let abs = (bytes.get(2).copied().unwrap_or(0) as u16) << 8 | lo as u16;
+        match op.1 {
+            AddressMode::IMP | AddressMode::IMM | AddressMode::REL => String::new(),
+            AddressMode::ZP => format!(" = {:02X}", self.peek(lo as u16)),
+            AddressMode::ZPX => {
+                let addr = lo.wrapping_add(self.r_x);
+                format!(" @ {:02X} = {:02X}", addr, self.peek(addr as u16))
+            },
+            AddressMode::ZPY => {
+                let addr = lo.wrapping_add(self.r_y);
+                format!(" @ {:02X} = {:02X}", addr, self.peek(addr as u16))
+            },
+            AddressMode::IZX => {
+                let ptr = lo.wrapping_add(self.r_x);
+                let addr = self.peek_zp_address(ptr);
+                format!(" @ {:02X} = {:04X} = {:02X}", ptr, addr, self.peek(addr))
+            },
+            AddressMode::IZY => {
+                let base = self.peek_zp_address(lo);
+                let addr = base.wrapping_add(self.r_y as u16);
+                format!(" = {:04X} @ {:04X} = {:02X}", base, addr, self.peek(addr))
+            },
+            AddressMode::ABS => match op.0 {
+                Instructions::JMP | Instructions::JSR => String::new(),
+                _ => format!(" = {:02X}", self.peek(abs))
+            },
+            AddressMode::ABX => {
+                let addr = abs.wrapping_add(self.r_x as u16);
+                format!(" @ {:04X} = {:02X}", addr, self.peek(addr))
+            },
+            AddressMode::ABY => {
+                let addr = abs.wrapping_add(self.r_y as u16);
+                format!(" @ {:04X} = {:02X}", addr, self.peek(addr))
+            },
+            AddressMode::IND => {
+                let addrh = self.peek((abs & 0xFF00) | (abs.wrapping_add(1) & 0x00FF)) as u16;
+                format!(" = {:04X}", addrh << 8 | self.peek(abs) as u16)
+            }
         }
     }
-    fn just_for_test(&mut self, op: &Op) -> Result<()> {
+
+    // operand and flag helpers
+
+    // fetches the operand bytes after the opcode and returns the effective
+    // address, plus whether indexing or branching crossed a page boundary
+    fn resolve_address(&mut self, mode: AddressMode) -> Result<(u16, bool)> {
+        let pc = self.r_pc;
+        let resolved = match mode {
+            AddressMode::IMM => (pc, false),
+            AddressMode::ZP  => (self.read(pc)? as u16, false),
+            AddressMode::ZPX => (self.read(pc)?.wrapping_add(self.r_x) as u16, false),
+            AddressMode::ZPY => (self.read(pc)?.wrapping_add(self.r_y) as u16, false),
+            AddressMode::IZX => {
+                let ptr = self.read(pc)?.wrapping_add(self.r_x);
+                (self.read_zp_address(ptr)?, false)
+            },
+            AddressMode::IZY => {
+                let ptr = self.read(pc)?;
+                let base = self.read_zp_address(ptr)?;
+                let addr = base.wrapping_add(self.r_y as u16);
+                (addr, page_crossed(base, addr))
+            },
+            AddressMode::ABS => (self.read_address(pc)?, false),
+            AddressMode::ABX => {
+                let base = self.read_address(pc)?;
+                let addr = base.wrapping_add(self.r_x as u16);
+                (addr, page_crossed(base, addr))
+            },
+            AddressMode::ABY => {
+                let base = self.read_address(pc)?;
+                let addr = base.wrapping_add(self.r_y as u16);
+                (addr, page_crossed(base, addr))
+            },
+            AddressMode::IND => {
+                // JMP ($xxFF) fetches the high byte from $xx00, not the next page
+                let ptr = self.read_address(pc)?;
+                let addrl = self.read(ptr)? as u16;
+                let addrh = self.read((ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF))? as u16;
+                ((addrh << 8) | addrl, false)
+            },
+            AddressMode::REL => {
+                let offset = self.read(pc)? as i8;
+                let next = pc.wrapping_add(1);
+                let target = next.wrapping_add(offset as u16);
+                (target, page_crossed(next, target))
+            },
+            AddressMode::IMP => return Err(anyhow!("implied mode has no operand, pc: {:#06x}", pc))
+        };
+        self.r_pc = pc.wrapping_add(mode_operand_len(mode));
+        Ok(resolved)
+    }
+
+    // pointers stored in zero page wrap around within zero page
+    fn read_zp_address(&mut self, ptr: u8) -> Result<u16> {
+        let addrl = self.read(ptr as u16)? as u16;
+        let addrh = self.read(ptr.wrapping_add(1) as u16)? as u16;
+        Ok((addrh << 8) | addrl)
+    }
+
+    fn operand_address(&mut self, mode: AddressMode) -> Result<u16> {
+        let (addr, crossed) = match self.resolved.take() {
+            Some(resolved) => resolved,
+            None => self.resolve_address(mode)?
+        };
+        self.page_crossed = crossed;
+        Ok(addr)
+    }
+
+    fn read_operand(&mut self, mode: AddressMode) -> Result<u8> {
+        let addr = self.operand_address(mode)?;
+        self.read(addr)
+    }
+
+    fn get_flag(&self, flag: u8) -> bool {
+        self.r_st & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.r_st |= flag;
+        } else {
+            self.r_st &= !flag;
+        }
+    }
+
+    fn update_nz(&mut self, data: u8) {
+        self.set_flag(STATUS_Z, data == 0);
+        self.set_flag(STATUS_N, data & 0x80 != 0);
+    }
+
+    fn push(&mut self, data: u8) -> Result<()> {
+        self.write(0x100 | self.r_sp as u16, data)?;
+        self.r_sp = self.r_sp.wrapping_sub(1);
         Ok(())
     }
 
-    fn write(&mut self, addr: u16, data: u8) -> Result<()> {
-        match addr {
-            addr if addr < 0x2000 => self.write_ram(addr & 0x07FF, data),
-            addr if addr < 0x4000 => self.write_ppu_register(addr & 0x2007, data),
-            addr if (addr < 0x4014 || addr == 0x4015) => self.write_apu_register(addr, data),
-            0x4014                => self.write_oamdma_addr(data),
-            0x4016                => self.write_joy1(data),
-            0x4017                => self.write_joy2(data),
-            addr if addr < 0x6000 => self.write_unused_addr(addr, data),
-            addr if addr < 0x8000 => self.write_cart(addr, data),
-            addr => self.write_unused_addr(addr, data)
+    fn pull(&mut self) -> Result<u8> {
+        self.r_sp = self.r_sp.wrapping_add(1);
+        self.read(0x100 | self.r_sp as u16)
+    }
+
+    fn push_address(&mut self, addr: u16) -> Result<()> {
+        self.push((addr >> 8) as u8)?;
+        self.push(addr as u8)
+    }
+
+    fn pull_address(&mut self) -> Result<u16> {
+        let addrl = self.pull()? as u16;
+        let addrh = self.pull()? as u16;
+        Ok((addrh << 8) | addrl)
+    }
+
+    // read-modify-write instructions operate on the accumulator in implied mode
+    fn modify<F>(&mut self, op: &Op, f: F) -> Result<u8>
+        where F: FnOnce(&mut Self, u8) -> u8 {
+        if op.1 == AddressMode::IMP {
+            let result = f(self, self.r_a);
+            self.r_a = result;
+            return Ok(result);
+        }
+        let addr = self.operand_address(op.1)?;
+        let data = self.read(addr)?;
+        let result = f(self, data);
+        self.write(addr, result)?;
+        Ok(result)
+    }
+
+    fn add_with_carry(&mut self, data: u8) {
+        if self.decimal_mode && self.get_flag(STATUS_D) {
+            return self.add_decimal(data);
+        }
+        self.add_with_carry_binary(data);
+    }
+
+    fn add_with_carry_binary(&mut self, data: u8) {
+        let sum = self.r_a as u16 + data as u16 + (self.r_st & STATUS_C) as u16;
+        let result = sum as u8;
+        self.set_flag(STATUS_C, sum > 0xFF);
+        self.set_flag(STATUS_V, (self.r_a ^ result) & (data ^ result) & 0x80 != 0);
+        self.r_a = result;
+        self.update_nz(result);
+    }
+
+    // the 2A03 has no decimal mode, so A - M - !C == A + !M + C
+    fn subtract_with_borrow(&mut self, data: u8) {
+        if self.decimal_mode && self.get_flag(STATUS_D) {
+            return self.subtract_decimal(data);
+        }
+        self.add_with_carry(!data);
+    }
+
+    // NMOS BCD: Z comes from the binary sum, N and V from the sum after
+    // the low nibble is adjusted
+    fn add_decimal(&mut self, data: u8) {
+        let carry = (self.r_st & STATUS_C) as u16;
+        let (a, m) = (self.r_a as u16, data as u16);
+        self.set_flag(STATUS_Z, (a + m + carry) & 0xFF == 0);
+        let mut lo = (a & 0x0F) + (m & 0x0F) + carry;
+        if lo > 0x09 {
+            lo += 0x06;
+        }
+        let mut hi = (a >> 4) + (m >> 4) + if lo > 0x0F {1} else {0};
+        let partial = ((hi << 4) & 0xFF) as u8;
+        self.set_flag(STATUS_N, partial & 0x80 != 0);
+        self.set_flag(STATUS_V, (self.r_a ^ partial) & !(self.r_a ^ data) & 0x80 != 0);
+        if hi > 0x09 {
+            hi += 0x06;
+        }
+        self.set_flag(STATUS_C, hi > 0x0F);
+        self.r_a = (((hi << 4) | (lo & 0x0F)) & 0xFF) as u8;
+    }
+
+    // NMOS BCD: all flags come from the binary subtraction
+    fn subtract_decimal(&mut self, data: u8) {
+        let borrow = 1 - (self.r_st & STATUS_C) as i16;
+        let (a, m) = (self.r_a as i16, data as i16);
+        let mut lo = (a & 0x0F) - (m & 0x0F) - borrow;
+        let mut hi = (a >> 4) - (m >> 4) - if lo < 0 {1} else {0};
+        if lo < 0 {
+            lo -= 0x06;
+        }
+        if hi < 0 {
+            hi -= 0x06;
+        }
+        self.add_with_carry_binary(!data);
+        self.r_a = (((hi << 4) | (lo & 0x0F)) & 0xFF) as u8;
+    }
+
+    fn compare(&mut self, reg: u8, data: u8) {
+        self.set_flag(STATUS_C, reg >= data);
+        self.update_nz(reg.wrapping_sub(data));
+    }
+
+    fn shift_left(&mut self, data: u8) -> u8 {
+        self.set_flag(STATUS_C, data & 0x80 != 0);
+        data << 1
+    }
+
+    fn shift_right(&mut self, data: u8) -> u8 {
+        self.set_flag(STATUS_C, data & 0x01 != 0);
+        data >> 1
+    }
+
+    fn rotate_left(&mut self, data: u8) -> u8 {
+        let carry = self.r_st & STATUS_C;
+        self.set_flag(STATUS_C, data & 0x80 != 0);
+        (data << 1) | carry
+    }
+
+    fn rotate_right(&mut self, data: u8) -> u8 {
+        let carry = self.r_st & STATUS_C;
+        self.set_flag(STATUS_C, data & 0x01 != 0);
+        (data >> 1) | (carry << 7)
+    }
+
+    fn service_interrupt(&mut self, tp: InteruptType) -> Result<()> {
+        // B is only set in the status copy pushed by BRK
+        let status = match tp {
+            InteruptType::BRK => self.r_st | STATUS_B | STATUS_U,
+            _ => (self.r_st & !STATUS_B) | STATUS_U
+        };
+        self.push_address(self.r_pc)?;
+        self.push(status)?;
+        self.set_flag(STATUS_I, true);
+        self.irq_inhibit = true;
+
+        let vector = match tp {
+            InteruptType::NMI => NMI_VECTOR,
+            InteruptType::IRQ => IRQ_VECTOR,
+            InteruptType::BRK => BRK_VECTOR
+        };
+        self.r_pc = self.read_address(vector)?;
+        // a later NMI takes over the vector through `interrupt`
+        if tp != InteruptType::NMI {
+            self.hijack_until = Some(self.cycles + 3);
         }
+        Ok(())
     }
 
-    fn write_ram(&mut self, addr: u16, data: u8) -> Result<()> {
-        debug!("write_cart called, address: {:#06x}, data: {:#04x}", addr, data);
-        self.ram[addr as usize] = data;
+    // instructions
+
+    fn exe_ora(&mut self, op: &Op) -> Result<()> {
+        self.r_a |= self.read_operand(op.1)?;
+        self.update_nz(self.r_a);
         Ok(())
     }
-    
-    fn write_cart(&mut self, addr: u16, data: u8) -> Result<()>{
-        debug!("write_cart called, address: {:#06x}, data: {:#04x}", addr, data);
-        self.cart.borrow_mut().write_sram(addr, data)
+
+    fn exe_and(&mut self, op: &Op) -> Result<()> {
+        self.r_a &= self.read_operand(op.1)?;
+        self.update_nz(self.r_a);
+        Ok(())
+    }
+
+    fn exe_eor(&mut self, op: &Op) -> Result<()> {
+        self.r_a ^= self.read_operand(op.1)?;
+        self.update_nz(self.r_a);
+        Ok(())
+    }
+
+    fn exe_adc(&mut self, op: &Op) -> Result<()> {
+        let data = self.read_operand(op.1)?;
+        self.add_with_carry(data);
+        Ok(())
+    }
+
+    fn exe_sbc(&mut self, op: &Op) -> Result<()> {
+        let data = self.read_operand(op.1)?;
+        self.subtract_with_borrow(data);
+        Ok(())
+    }
+
+    fn exe_lda(&mut self, op: &Op) -> Result<()> {
+        self.r_a = self.read_operand(op.1)?;
+        self.update_nz(self.r_a);
+        Ok(())
+    }
+
+    fn exe_ldx(&mut self, op: &Op) -> Result<()> {
+        self.r_x = self.read_operand(op.1)?;
+        self.update_nz(self.r_x);
+        Ok(())
+    }
+
+    fn exe_ldy(&mut self, op: &Op) -> Result<()> {
+        self.r_y = self.read_operand(op.1)?;
+        self.update_nz(self.r_y);
+        Ok(())
+    }
+
+    fn exe_sta(&mut self, op: &Op) -> Result<()> {
+        let addr = self.operand_address(op.1)?;
+        self.write(addr, self.r_a)
+    }
+
+    fn exe_stx(&mut self, op: &Op) -> Result<()> {
+        let addr = self.operand_address(op.1)?;
+        self.write(addr, self.r_x)
+    }
+
+    fn exe_sty(&mut self, op: &Op) -> Result<()> {
+        let addr = self.operand_address(op.1)?;
+        self.write(addr, self.r_y)
+    }
+
+    fn exe_compare(&mut self, op: &Op, reg: u8) -> Result<()> {
+        let data = self.read_operand(op.1)?;
+        self.compare(reg, data);
+        Ok(())
+    }
+
+    fn exe_dec(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, |_, data| data.wrapping_sub(1))?;
+        self.update_nz(result);
+        Ok(())
+    }
+
+    fn exe_inc(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, |_, data| data.wrapping_add(1))?;
+        self.update_nz(result);
+        Ok(())
+    }
+
+    fn exe_dex(&mut self) -> Result<()> {
+        self.r_x = self.r_x.wrapping_sub(1);
+        self.update_nz(self.r_x);
+        Ok(())
+    }
+
+    fn exe_dey(&mut self) -> Result<()> {
+        self.r_y = self.r_y.wrapping_sub(1);
+        self.update_nz(self.r_y);
+        Ok(())
+    }
+
+    fn exe_inx(&mut self) -> Result<()> {
+        self.r_x = self.r_x.wrapping_add(1);
+        self.update_nz(self.r_x);
+        Ok(())
+    }
+
+    fn exe_iny(&mut self) -> Result<()> {
+        self.r_y = self.r_y.wrapping_add(1);
+        self.update_nz(self.r_y);
+        Ok(())
+    }
+
+    fn exe_asl(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, Self::shift_left)?;
+        self.update_nz(result);
+        Ok(())
+    }
+
+    fn exe_lsr(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, Self::shift_right)?;
+        self.update_nz(result);
+        Ok(())
+    }
+
+    fn exe_rol(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, Self::rotate_left)?;
+        self.update_nz(result);
+        Ok(())
+    }
+
+    fn exe_ror(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, Self::rotate_right)?;
+        self.update_nz(result);
+        Ok(())
+    }
+
+    fn exe_tax(&mut self) -> Result<()> {
+        self.r_x = self.r_a;
+        self.update_nz(self.r_x);
+        Ok(())
+    }
+
+    fn exe_txa(&mut self) -> Result<()> {
+        self.r_a = self.r_x;
+        self.update_nz(self.r_a);
+        Ok(())
     }
-    fn write_joy1(&mut self, data: u8) -> Result<()> {
-        debug!("write_joy1 called, data: {:#04x}", data);
+
+    fn exe_tay(&mut self) -> Result<()> {
+        self.r_y = self.r_a;
+        self.update_nz(self.r_y);
         Ok(())
     }
-    fn write_joy2(&mut self, data: u8) -> Result<()> {
-        debug!("write_joy2 called, data: {:#04x}", data);
+
+    fn exe_tya(&mut self) -> Result<()> {
+        self.r_a = self.r_y;
+        self.update_nz(self.r_a);
         Ok(())
     }
-    fn write_unused_addr(&mut self, addr: u16, data: u8) -> Result<()> {
-        debug!("write_unused_addr called, address: {:#06x}, data: {:#04x}", addr, data);
+
+    fn exe_tsx(&mut self) -> Result<()> {
+        self.r_x = self.r_sp;
+        self.update_nz(self.r_x);
         Ok(())
     }
-    fn write_ppu_register(&mut self, addr: u16, data: u8) -> Result<()> {
-        debug!("write_ppu_register called, address: {:#06x}, data: {:#04x}", addr, data);
+
+    fn exe_txs(&mut self) -> Result<()> {
+        self.r_sp = self.r_x;
         Ok(())
     }
-    fn write_apu_register(&mut self, addr: u16, data: u8) -> Result<()> {
-        debug!("write_apu_register called, address: {:#06x}, data: {:#04x}", addr, data);
+
+    fn exe_pla(&mut self) -> Result<()> {
+        self.r_a = self.pull()?;
+        self.update_nz(self.r_a);
         Ok(())
     }
-    fn write_oamdma_addr(&mut self, data: u8) -> Result<()> {
-        debug!("write_oamdma_addr called, data: {:#04x}", data);
+
+    fn exe_pha(&mut self) -> Result<()> {
+        self.push(self.r_a)
+    }
+
+    fn exe_plp(&mut self) -> Result<()> {
+        // B only exists on the stack copy, bit 5 always reads back as set
+        let status = self.pull()?;
+        self.r_st = (status & !STATUS_B) | STATUS_U;
         Ok(())
     }
 
-    fn read(&self, addr: u16) -> Result<u8> {
-        match addr {
-            addr if addr < 0x2000 => self.read_ram(addr & 0x07FF),
-            addr if addr < 0x4000 => self.read_ppu_register(addr & 0x2007),
-            addr if (addr < 0x4014 || addr == 0x4015) => self.read_apu_register(addr),
-            0x4014                => self.read_oamdma_addr(),
-            0x4016                => self.read_joy1(),
-            0x4017                => self.read_joy2(),
-            addr if addr < 0x6000 => self.read_unused_addr(addr),
-            addr if addr < 0x8000 => self.read_cart(addr),
-            addr => self.read_unused_addr(addr)
+    fn exe_php(&mut self) -> Result<()> {
+        self.push(self.r_st | STATUS_B | STATUS_U)
+    }
+
+    fn exe_branch(&mut self, op: &Op, cond: bool) -> Result<()> {
+        let target = self.operand_address(op.1)?;
+        if cond {
+            // taken branches cost one cycle, the page-cross flag adds another
+            self.r_pc = target;
+            self.extra_cycles += 1;
+        } else {
+            self.page_crossed = false;
         }
+        Ok(())
     }
 
-    
-    fn read_ram(&self, addr: u16) -> Result<u8> {
-        debug!("read_ram called, address: {:#06x}", addr);
-        Ok(self.ram[addr as usize])
+    fn exe_flag(&mut self, flag: u8, on: bool) -> Result<()> {
+        self.set_flag(flag, on);
+        Ok(())
     }
-    fn read_cart(&self, addr: u16) -> Result<u8> {
-        self.cart.borrow().read_prg(addr)
+
+    fn exe_jsr(&mut self, op: &Op) -> Result<()> {
+        let target = self.operand_address(op.1)?;
+        // the pushed return address points at the last byte of the JSR
+        self.push_address(self.r_pc.wrapping_sub(1))?;
+        self.r_pc = target;
+        Ok(())
     }
-    fn read_joy1(&self) -> Result<u8> {
-        debug!("read_joy1 called");
-        Ok(0)
+
+    fn exe_jmp(&mut self, op: &Op) -> Result<()> {
+        self.r_pc = self.operand_address(op.1)?;
+        Ok(())
     }
-    fn read_joy2(&self) -> Result<u8> {
-        debug!("read_joy2 called");
-        Ok(0)
+
+    fn exe_rts(&mut self) -> Result<()> {
+        self.r_pc = self.pull_address()?.wrapping_add(1);
+        Ok(())
     }
-    fn read_unused_addr(&self, addr: u16) -> Result<u8> {
-        debug!("read_unused_addr called, addr: {:#06x}", addr);
-        Ok(0)
+
+    fn exe_rti(&mut self) -> Result<()> {
+        let status = self.pull()?;
+        self.r_st = (status & !STATUS_B) | STATUS_U;
+        self.r_pc = self.pull_address()?;
+        Ok(())
     }
-    fn read_ppu_register(&self, addr: u16) -> Result<u8> {
-        debug!("read_ppu_register called, addr: {:#06x}", addr);
-        Ok(0)
+
+    fn exe_bit(&mut self, op: &Op) -> Result<()> {
+        let data = self.read_operand(op.1)?;
+        self.set_flag(STATUS_Z, self.r_a & data == 0);
+        self.set_flag(STATUS_N, data & 0x80 != 0);
+        self.set_flag(STATUS_V, data & 0x40 != 0);
+        Ok(())
     }
-    fn read_apu_register(&self, addr: u16) -> Result<u8> {
-        debug!("read_apu_register called, addr: {:#06x}", addr);
-        Ok(0)
+
+    fn exe_brk(&mut self) -> Result<()> {
+        // BRK skips a padding byte, so the return address is opcode + 2
+        self.r_pc = self.r_pc.wrapping_add(1);
+        self.service_interrupt(InteruptType::BRK)
     }
-    fn read_oamdma_addr(&self) -> Result<u8> {
-        debug!("read_oamdma_addr called");
-        Ok(0)
+
+    fn exe_nop(&mut self, op: &Op) -> Result<()> {
+        // unofficial NOPs with an operand still perform the read
+        if op.1 != AddressMode::IMP {
+            self.read_operand(op.1)?;
+        }
+        Ok(())
     }
 
-    fn exe_ora(&mut self, op: &Op) -> Result<()> {
+    // unofficial instructions
+
+    fn exe_slo(&mut self, op: &Op) -> Result<()> {
+        self.r_a |= self.modify(op, Self::shift_left)?;
+        self.update_nz(self.r_a);
+        Ok(())
+    }
+
+    fn exe_rla(&mut self, op: &Op) -> Result<()> {
+        self.r_a &= self.modify(op, Self::rotate_left)?;
+        self.update_nz(self.r_a);
         Ok(())
     }
+
+    fn exe_sre(&mut self, op: &Op) -> Result<()> {
+        self.r_a ^= self.modify(op, Self::shift_right)?;
+        self.update_nz(self.r_a);
+        Ok(())
+    }
+
+    fn exe_rra(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, Self::rotate_right)?;
+        self.add_with_carry(result);
+        Ok(())
+    }
+
+    fn exe_sax(&mut self, op: &Op) -> Result<()> {
+        let addr = self.operand_address(op.1)?;
+        self.write(addr, self.r_a & self.r_x)
+    }
+
+    fn exe_lax(&mut self, op: &Op) -> Result<()> {
+        self.r_a = self.read_operand(op.1)?;
+        self.r_x = self.r_a;
+        self.update_nz(self.r_a);
+        Ok(())
+    }
+
+    fn exe_dcp(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, |_, data| data.wrapping_sub(1))?;
+        self.compare(self.r_a, result);
+        Ok(())
+    }
+
+    fn exe_isc(&mut self, op: &Op) -> Result<()> {
+        let result = self.modify(op, |_, data| data.wrapping_add(1))?;
+        self.subtract_with_borrow(result);
+        Ok(())
+    }
+
+    fn exe_anc(&mut self, op: &Op) -> Result<()> {
+        self.r_a &= self.read_operand(op.1)?;
+        self.update_nz(self.r_a);
+        self.set_flag(STATUS_C, self.r_a & 0x80 != 0);
+        Ok(())
+    }
+
+    fn exe_alr(&mut self, op: &Op) -> Result<()> {
+        let data = self.r_a & self.read_operand(op.1)?;
+        self.r_a = self.shift_right(data);
+        self.update_nz(self.r_a);
+        Ok(())
+    }
+
+    fn exe_arr(&mut self, op: &Op) -> Result<()> {
+        // AND + ROR, but C and V come from bits 6 and 5 of the result
+        let data = self.r_a & self.read_operand(op.1)?;
+        self.r_a = (data >> 1) | ((self.r_st & STATUS_C) << 7);
+        self.update_nz(self.r_a);
+        self.set_flag(STATUS_C, self.r_a & 0x40 != 0);
+        self.set_flag(STATUS_V, ((self.r_a >> 6) ^ (self.r_a >> 5)) & 0x01 != 0);
+        Ok(())
+    }
+
+    fn exe_axs(&mut self, op: &Op) -> Result<()> {
+        let data = self.read_operand(op.1)?;
+        let base = self.r_a & self.r_x;
+        self.set_flag(STATUS_C, base >= data);
+        self.r_x = base.wrapping_sub(data);
+        self.update_nz(self.r_x);
+        Ok(())
+    }
+}
+
+// nestest.log spells ISC as ISB
+fn trace_mnemonic(ins: Instructions) -> String {
+    match ins {
+        Instructions::ISC => "ISB".to_string(),
+        ins => format!("{:?}", ins)
+    }
+}
+
+fn page_crossed(from: u16, to: u16) -> bool {
+    from & 0xFF00 != to & 0xFF00
+}
+
+fn mode_operand_len(mode: AddressMode) -> u16 {
+    match mode {
+        AddressMode::IMP => 0,
+        AddressMode::ABS | AddressMode::ABX | AddressMode::ABY | AddressMode::IND => 2,
+        _ => 1
+    }
 }
 
 
 #[derive(PartialEq, Clone, Copy, Debug)]
-enum AddressMode {
+pub enum AddressMode {
     IMM,ZP,ZPX,ZPY,IZX,IZY,ABS,ABX,ABY,IND,REL,IMP
 }
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum InteruptType {
-    NMI
+    NMI,
+    IRQ,
+    BRK
 }
 
 type OpLen = usize;
 type OpCycles = usize;
-type Op = (Instructions, AddressMode, OpLen, OpCycles, bool);
+pub(crate) type Op = (Instructions, AddressMode, OpLen, OpCycles, bool);
 
-#[derive(Debug)]
-enum Instructions {
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Instructions {
     ORA,AND,EOR,ADC,SBC,LDA,STA,LDX,STX,LDY,STY,
     CMP,CPX,CPY,DEC,INC,
     DEX,DEY,INX,INY,ASL,ROL,LSR,ROR,TAX,TXA,
@@ -226,13 +1146,54 @@ enum Instructions {
     BPL,BMI,BVC,BVS,BCC,BCS,BNE,BEQ,
     CLC,SEC,CLD,SED,CLI,SEI,CLV,
     JSR,JMP,RTS,RTI,BIT,
-    BRK
-    // SLO,RLA,RRA,SAX,LAX,DCP,ISC,ANC,ALR,ARR,XAA,LAX
+    BRK,NOP,
+    // unofficial
+    SLO,RLA,RRA,SRE,SAX,LAX,DCP,ISC,ANC,ALR,ARR,AXS
 }
 
+impl Instructions {
+    pub fn is_unofficial(self, opcode: u8) -> bool {
+        match self {
+            Instructions::SLO | Instructions::RLA | Instructions::RRA |
+            Instructions::SRE | Instructions::SAX | Instructions::LAX |
+            Instructions::DCP | Instructions::ISC | Instructions::ANC |
+            Instructions::ALR | Instructions::ARR | Instructions::AXS => true,
+            Instructions::NOP => opcode != 0xea,
+            Instructions::SBC => opcode == 0xeb,
+            _ => false
+        }
+    }
+}
+
+
+// instructions that read or write memory through their operand, as
+// opposed to jumps, which only fetch their target
+fn defers_access(op: &Op) -> bool {
+    match op.1 {
+        AddressMode::ZP | AddressMode::ZPX | AddressMode::ZPY | AddressMode::IZX |
+        AddressMode::IZY | AddressMode::ABS | AddressMode::ABX | AddressMode::ABY =>
+            !matches!(op.0, Instructions::JMP | Instructions::JSR),
+        _ => false
+    }
+}
+
+// write cycles at the end of an instruction, stores and read-modify-write
+// instructions finish by writing memory
+fn trailing_writes(op: &Op) -> usize {
+    match op.0 {
+        Instructions::STA | Instructions::STX | Instructions::STY | Instructions::SAX |
+        Instructions::PHA | Instructions::PHP => 1,
+        Instructions::ASL | Instructions::LSR | Instructions::ROL | Instructions::ROR |
+        Instructions::INC | Instructions::DEC | Instructions::SLO | Instructions::RLA |
+        Instructions::SRE | Instructions::RRA | Instructions::DCP | Instructions::ISC
+            if op.1 != AddressMode::IMP => 2,
+        _ => 0
+    }
+}
 
 static STATUS_N: u8 = 0x80;
 static STATUS_V: u8 = 0x40;
+static STATUS_U: u8 = 0x20;
 static STATUS_B: u8 = 0x10;
 static STATUS_D: u8 = 0x08;
 static STATUS_I: u8 = 0x04;
@@ -242,12 +1203,13 @@ static STATUS_C: u8 = 0x01;
 static RESET_VECTOR: u16 = 0xFFFC;
 static BRK_VECTOR: u16 = 0xFFFE;
 static IRQ_VECTOR: u16 = 0xFFFE;
-static NMI_VECTOR: u16 = 0xFFFE;
-static STATUS_START: u8 = 0x04;
+static NMI_VECTOR: u16 = 0xFFFA;
+static STATUS_START: u8 = 0x24;
 static STACK_START: u8 = 0xFD;
+static INTERRUPT_CYCLES: usize = 7;
 
 lazy_static! {
-    static ref OP_MAP: HashMap<u8, Op> = {
+    pub(crate) static ref OP_MAP: HashMap<u8, Op> = {
         let mut m = HashMap::new();
         m.insert(0x69, (Instructions::ADC, AddressMode::IMM,  2,  2, false));
         m.insert(0x65, (Instructions::ADC, AddressMode::ZP,  2,  3, false));
@@ -270,12 +1232,12 @@ lazy_static! {
         m.insert(0x16, (Instructions::ASL, AddressMode::ZPX,  2,  6, false));
         m.insert(0x0e, (Instructions::ASL, AddressMode::ABS,  3,  6, false));
         m.insert(0x1e, (Instructions::ASL, AddressMode::ABX,  3,  7, false));
-        m.insert(0x90, (Instructions::BCC, AddressMode::REL,  2,  2, false));
+        m.insert(0x90, (Instructions::BCC, AddressMode::REL,  2,  2, true));
         m.insert(0xb0, (Instructions::BCS, AddressMode::REL,  2,  2, true));
         m.insert(0xf0, (Instructions::BEQ, AddressMode::REL,  2,  2, true));
         m.insert(0x24, (Instructions::BIT, AddressMode::ZP,  2,  3, false));
         m.insert(0x2c, (Instructions::BIT, AddressMode::ABS,  3,  4, false));
-        m.insert(0x30, (Instructions::BMI, AddressMode::REL,  2,  2, false));
+        m.insert(0x30, (Instructions::BMI, AddressMode::REL,  2,  2, true));
         m.insert(0xd0, (Instructions::BNE, AddressMode::REL,  2,  2, true));
         m.insert(0x10, (Instructions::BPL, AddressMode::REL,  2,  2, true));
         m.insert(0x00, (Instructions::BRK, AddressMode::IMP,  1,  7, false));
@@ -345,13 +1307,13 @@ lazy_static! {
         m.insert(0x56, (Instructions::LSR, AddressMode::ZPX,  2,  6, false));
         m.insert(0x4e, (Instructions::LSR, AddressMode::ABS,  3,  6, false));
         m.insert(0x5e, (Instructions::LSR, AddressMode::ABX,  3,  7, false));
-        // m.insert(0x1a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
-        // m.insert(0x3a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
-        // m.insert(0x5a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
-        // m.insert(0x7a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
-        // m.insert(0xda, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
-        // m.insert(0xea, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
-        // m.insert(0xfa, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
+        m.insert(0x1a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
+        m.insert(0x3a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
+        m.insert(0x5a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
+        m.insert(0x7a, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
+        m.insert(0xda, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
+        m.insert(0xea, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
+        m.insert(0xfa, (Instructions::NOP, AddressMode::IMP,  1,  2, false));
         m.insert(0x09, (Instructions::ORA, AddressMode::IMM,  2,  2, false));
         m.insert(0x05, (Instructions::ORA, AddressMode::ZP,  2,  3, false));
         m.insert(0x15, (Instructions::ORA, AddressMode::ZPX,  2,  4, false));
@@ -393,7 +1355,7 @@ lazy_static! {
         m.insert(0x9d, (Instructions::STA, AddressMode::ABX,  3,  5, false));
         m.insert(0x99, (Instructions::STA, AddressMode::ABY,  3,  5, false));
         m.insert(0x81, (Instructions::STA, AddressMode::IZX,  2,  6, false));
-        m.insert(0x91, (Instructions::STA, AddressMode::IZY,  2,  6, true));
+        m.insert(0x91, (Instructions::STA, AddressMode::IZY,  2,  6, false));
         m.insert(0x86, (Instructions::STX, AddressMode::ZP,  2,  3, false));
         m.insert(0x96, (Instructions::STX, AddressMode::ZPY,  2,  4, false));
         m.insert(0x8e, (Instructions::STX, AddressMode::ABS,  3,  4, false));
@@ -407,63 +1369,609 @@ lazy_static! {
         m.insert(0x9a, (Instructions::TXS, AddressMode::IMP,  1,  2, false));
         m.insert(0x98, (Instructions::TYA, AddressMode::IMP,  1,  2, false));
         //Illegal opcodes
-        // m.insert(0x4b, (Instructions::ALR, AddressMode::IMM,  2,  2));
-        // m.insert(0x0b, (Instructions::ANC, AddressMode::IMM,  2,  2));
-        // m.insert(0x2b, (Instructions::ANC, AddressMode::IMM,  2,  2));
-        // m.insert(0x6b, (Instructions::ARR, AddressMode::IMM,  2,  2));
-        // m.insert(0xcb, (Instructions::AXS, AddressMode::IMM,  2,  2));
-        // m.insert(0xa3, (Instructions::LAX, AddressMode::IZX,  2,  6));
-        // m.insert(0xa7, (Instructions::LAX, AddressMode::ZP,  2,  3));
-        // m.insert(0xaf, (Instructions::LAX, AddressMode::ABS,  3,  4));
-        // m.insert(0xb3, (Instructions::LAX, AddressMode::IZY,  2,  5));
-        // m.insert(0xb7, (Instructions::LAX, AddressMode::ZPY,  2,  4));
-        // m.insert(0xbf, (Instructions::LAX, AddressMode::ABY,  3,  4));
-        // m.insert(0x83, (Instructions::SAX, AddressMode::IZX,  2,  6));
-        // m.insert(0x87, (Instructions::SAX, AddressMode::ZP,  2,  3));
-        // m.insert(0x8f, (Instructions::SAX, AddressMode::ABS,  3,  4));
-        // m.insert(0x97, (Instructions::SAX, AddressMode::ZPY,  2,  4));
-        // m.insert(0xc3, (Instructions::DCP, AddressMode::IZX,  2,  8));
-        // m.insert(0xc7, (Instructions::DCP, AddressMode::ZP,  2,  5));
-        // m.insert(0xcf, (Instructions::DCP, AddressMode::ABS,  3,  6));
-        // m.insert(0xd3, (Instructions::DCP, AddressMode::IZY,  2,  8));
-        // m.insert(0xd7, (Instructions::DCP, AddressMode::ZPX,  2,  6));
-        // m.insert(0xdb, (Instructions::DCP, AddressMode::ABY,  3,  7));
-        // m.insert(0xdf, (Instructions::DCP, AddressMode::ABX,  3,  7));
-        // m.insert(0xe3, (Instructions::ISC, AddressMode::IZX,  2,  8));
-        // m.insert(0xe7, (Instructions::ISC, AddressMode::ZP,  2,  5));
-        // m.insert(0xef, (Instructions::ISC, AddressMode::ABS,  3,  6));
-        // m.insert(0xf3, (Instructions::ISC, AddressMode::IZY,  2,  8));
-        // m.insert(0xf7, (Instructions::ISC, AddressMode::ZPX,  2,  6));
-        // m.insert(0xfb, (Instructions::ISC, AddressMode::ABY,  3,  7));
-        // m.insert(0xff, (Instructions::ISC, AddressMode::ABX,  3,  7));
-        // m.insert(0x23, (Instructions::RLA, AddressMode::IZX,  2,  8));
-        // m.insert(0x27, (Instructions::RLA, AddressMode::ZP,  2,  5));
-        // m.insert(0x2f, (Instructions::RLA, AddressMode::ABS,  3,  6));
-        // m.insert(0x33, (Instructions::RLA, AddressMode::IZY,  2,  8));
-        // m.insert(0x37, (Instructions::RLA, AddressMode::ZPX,  2,  6));
-        // m.insert(0x3b, (Instructions::RLA, AddressMode::ABY,  3,  7));
-        // m.insert(0x3f, (Instructions::RLA, AddressMode::ABX,  3,  7));
-        // m.insert(0x63, (Instructions::RRA, AddressMode::IZX,  2,  8));
-        // m.insert(0x67, (Instructions::RRA, AddressMode::ZP,  2,  5));
-        // m.insert(0x6f, (Instructions::RRA, AddressMode::ABS,  3,  6));
-        // m.insert(0x73, (Instructions::RRA, AddressMode::IZY,  2,  8));
-        // m.insert(0x77, (Instructions::RRA, AddressMode::ZPX,  2,  6));
-        // m.insert(0x7b, (Instructions::RRA, AddressMode::ABY,  3,  7));
-        // m.insert(0x7f, (Instructions::RRA, AddressMode::ABX,  3,  7));
-        // m.insert(0x03, (Instructions::SLO, AddressMode::IZX,  2,  8));
-        // m.insert(0x07, (Instructions::SLO, AddressMode::ZP,  2,  5));
-        // m.insert(0x0f, (Instructions::SLO, AddressMode::ABS,  3,  6));
-        // m.insert(0x13, (Instructions::SLO, AddressMode::IZY,  2,  8));
-        // m.insert(0x17, (Instructions::SLO, AddressMode::ZPX,  2,  6));
-        // m.insert(0x1b, (Instructions::SLO, AddressMode::ABY,  3,  7));
-        // m.insert(0x1f, (Instructions::SLO, AddressMode::ABX,  3,  7));
-        // m.insert(0x43, (Instructions::SRE, AddressMode::IZX,  2,  8));
-        // m.insert(0x47, (Instructions::SRE, AddressMode::ZP,  2,  5));
-        // m.insert(0x4f, (Instructions::SRE, AddressMode::ABS,  3,  6));
-        // m.insert(0x53, (Instructions::SRE, AddressMode::IZY,  2,  8));
-        // m.insert(0x57, (Instructions::SRE, AddressMode::ZPX,  2,  6));
-        // m.insert(0x5b, (Instructions::SRE, AddressMode::ABY,  3,  7));
-        // m.insert(0x5f, (Instructions::SRE, AddressMode::ABX,  3,  7));
+        m.insert(0x4b, (Instructions::ALR, AddressMode::IMM,  2,  2, false));
+        m.insert(0x0b, (Instructions::ANC, AddressMode::IMM,  2,  2, false));
+        m.insert(0x2b, (Instructions::ANC, AddressMode::IMM,  2,  2, false));
+        m.insert(0x6b, (Instructions::ARR, AddressMode::IMM,  2,  2, false));
+        m.insert(0xcb, (Instructions::AXS, AddressMode::IMM,  2,  2, false));
+        m.insert(0xa3, (Instructions::LAX, AddressMode::IZX,  2,  6, false));
+        m.insert(0xa7, (Instructions::LAX, AddressMode::ZP,  2,  3, false));
+        m.insert(0xaf, (Instructions::LAX, AddressMode::ABS,  3,  4, false));
+        m.insert(0xb3, (Instructions::LAX, AddressMode::IZY,  2,  5, true));
+        m.insert(0xb7, (Instructions::LAX, AddressMode::ZPY,  2,  4, false));
+        m.insert(0xbf, (Instructions::LAX, AddressMode::ABY,  3,  4, true));
+        m.insert(0x83, (Instructions::SAX, AddressMode::IZX,  2,  6, false));
+        m.insert(0x87, (Instructions::SAX, AddressMode::ZP,  2,  3, false));
+        m.insert(0x8f, (Instructions::SAX, AddressMode::ABS,  3,  4, false));
+        m.insert(0x97, (Instructions::SAX, AddressMode::ZPY,  2,  4, false));
+        m.insert(0xc3, (Instructions::DCP, AddressMode::IZX,  2,  8, false));
+        m.insert(0xc7, (Instructions::DCP, AddressMode::ZP,  2,  5, false));
+        m.insert(0xcf, (Instructions::DCP, AddressMode::ABS,  3,  6, false));
+        m.insert(0xd3, (Instructions::DCP, AddressMode::IZY,  2,  8, false));
+        m.insert(0xd7, (Instructions::DCP, AddressMode::ZPX,  2,  6, false));
+        m.insert(0xdb, (Instructions::DCP, AddressMode::ABY,  3,  7, false));
+        m.insert(0xdf, (Instructions::DCP, AddressMode::ABX,  3,  7, false));
+        m.insert(0xe3, (Instructions::ISC, AddressMode::IZX,  2,  8, false));
+        m.insert(0xe7, (Instructions::ISC, AddressMode::ZP,  2,  5, false));
+        m.insert(0xef, (Instructions::ISC, AddressMode::ABS,  3,  6, false));
+        m.insert(0xf3, (Instructions::ISC, AddressMode::IZY,  2,  8, false));
+        m.insert(0xf7, (Instructions::ISC, AddressMode::ZPX,  2,  6, false));
+        m.insert(0xfb, (Instructions::ISC, AddressMode::ABY,  3,  7, false));
+        m.insert(0xff, (Instructions::ISC, AddressMode::ABX,  3,  7, false));
+        m.insert(0x23, (Instructions::RLA, AddressMode::IZX,  2,  8, false));
+        m.insert(0x27, (Instructions::RLA, AddressMode::ZP,  2,  5, false));
+        m.insert(0x2f, (Instructions::RLA, AddressMode::ABS,  3,  6, false));
+        m.insert(0x33, (Instructions::RLA, AddressMode::IZY,  2,  8, false));
+        m.insert(0x37, (Instructions::RLA, AddressMode::ZPX,  2,  6, false));
+        m.insert(0x3b, (Instructions::RLA, AddressMode::ABY,  3,  7, false));
+        m.insert(0x3f, (Instructions::RLA, AddressMode::ABX,  3,  7, false));
+        m.insert(0x63, (Instructions::RRA, AddressMode::IZX,  2,  8, false));
+        m.insert(0x67, (Instructions::RRA, AddressMode::ZP,  2,  5, false));
+        m.insert(0x6f, (Instructions::RRA, AddressMode::ABS,  3,  6, false));
+        m.insert(0x73, (Instructions::RRA, AddressMode::IZY,  2,  8, false));
+        m.insert(0x77, (Instructions::RRA, AddressMode::ZPX,  2,  6, false));
+        m.insert(0x7b, (Instructions::RRA, AddressMode::ABY,  3,  7, false));
+        m.insert(0x7f, (Instructions::RRA, AddressMode::ABX,  3,  7, false));
+        m.insert(0x03, (Instructions::SLO, AddressMode::IZX,  2,  8, false));
+        m.insert(0x07, (Instructions::SLO, AddressMode::ZP,  2,  5, false));
+        m.insert(0x0f, (Instructions::SLO, AddressMode::ABS,  3,  6, false));
+        m.insert(0x13, (Instructions::SLO, AddressMode::IZY,  2,  8, false));
+        m.insert(0x17, (Instructions::SLO, AddressMode::ZPX,  2,  6, false));
+        m.insert(0x1b, (Instructions::SLO, AddressMode::ABY,  3,  7, false));
+        m.insert(0x1f, (Instructions::SLO, AddressMode::ABX,  3,  7, false));
+        m.insert(0x43, (Instructions::SRE, AddressMode::IZX,  2,  8, false));
+        m.insert(0x47, (Instructions::SRE, AddressMode::ZP,  2,  5, false));
+        m.insert(0x4f, (Instructions::SRE, AddressMode::ABS,  3,  6, false));
+        m.insert(0x53, (Instructions::SRE, AddressMode::IZY,  2,  8, false));
+        m.insert(0x57, (Instructions::SRE, AddressMode::ZPX,  2,  6, false));
+        m.insert(0x5b, (Instructions::SRE, AddressMode::ABY,  3,  7, false));
+        m.insert(0x5f, (Instructions::SRE, AddressMode::ABX,  3,  7, false));
+        m.insert(0xeb, (Instructions::SBC, AddressMode::IMM,  2,  2, false));
+        m.insert(0x80, (Instructions::NOP, AddressMode::IMM,  2,  2, false));
+        m.insert(0x82, (Instructions::NOP, AddressMode::IMM,  2,  2, false));
+        m.insert(0x89, (Instructions::NOP, AddressMode::IMM,  2,  2, false));
+        m.insert(0xc2, (Instructions::NOP, AddressMode::IMM,  2,  2, false));
+        m.insert(0xe2, (Instructions::NOP, AddressMode::IMM,  2,  2, false));
+        m.insert(0x04, (Instructions::NOP, AddressMode::ZP,  2,  3, false));
+        m.insert(0x44, (Instructions::NOP, AddressMode::ZP,  2,  3, false));
+        m.insert(0x64, (Instructions::NOP, AddressMode::ZP,  2,  3, false));
+        m.insert(0x14, (Instructions::NOP, AddressMode::ZPX,  2,  4, false));
+        m.insert(0x34, (Instructions::NOP, AddressMode::ZPX,  2,  4, false));
+        m.insert(0x54, (Instructions::NOP, AddressMode::ZPX,  2,  4, false));
+        m.insert(0x74, (Instructions::NOP, AddressMode::ZPX,  2,  4, false));
+        m.insert(0xd4, (Instructions::NOP, AddressMode::ZPX,  2,  4, false));
+        m.insert(0xf4, (Instructions::NOP, AddressMode::ZPX,  2,  4, false));
+        m.insert(0x0c, (Instructions::NOP, AddressMode::ABS,  3,  4, false));
+        m.insert(0x1c, (Instructions::NOP, AddressMode::ABX,  3,  4, true));
+        m.insert(0x3c, (Instructions::NOP, AddressMode::ABX,  3,  4, true));
+        m.insert(0x5c, (Instructions::NOP, AddressMode::ABX,  3,  4, true));
+        m.insert(0x7c, (Instructions::NOP, AddressMode::ABX,  3,  4, true));
+        m.insert(0xdc, (Instructions::NOP, AddressMode::ABX,  3,  4, true));
+        m.insert(0xfc, (Instructions::NOP, AddressMode::ABX,  3,  4, true));
         m
     };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::bus::FlatRam;
+    use crate::cart;
+    use crate::controller;
+
+    // NROM image whose NMI/RESET/IRQ vectors point at $0400/$0200/$0500
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x01, 0x00];
+        rom.resize(16, 0);
+        let mut prg = vec![0u8; 0x8000];
+        prg[0x7ffa..].copy_from_slice(&[0x00, 0x04, 0x00, 0x02, 0x00, 0x05]);
+        rom.extend(prg);
+        rom.extend(vec![0u8; 0x2000]);
+        rom
+    }
+
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let mut cart = cart::Cartridge::new();
+        cart.load_from_bytes(&test_rom()).unwrap();
+        let mut cpu = CPU::new(SystemBus::new(cart));
+        cpu.bus.ram[0x200..0x200 + program.len()].copy_from_slice(program);
+        cpu.r_pc = 0x200;
+        cpu.r_sp = STACK_START;
+        cpu.r_st = STATUS_START;
+        cpu
+    }
+
+    fn run_instructions(cpu: &mut CPU, count: usize) {
+        for _ in 0..count {
+            cpu.step_instruction().unwrap();
+        }
+    }
+
+    #[test]
+    fn oam_dma_copies_page_and_stalls() {
+        // LDA #$10; STA $2003; LDA #$03; STA $4014; LDA $00; STA $4014
+        let mut cpu = cpu_with_program(&[
+            0xa9, 0x10, 0x8d, 0x03, 0x20, 0xa9, 0x03, 0x8d, 0x14, 0x40,
+            0xa5, 0x00, 0x8d, 0x14, 0x40
+        ]);
+        for i in 0..256 {
+            cpu.bus.ram[0x300 + i] = i as u8;
+        }
+        run_instructions(&mut cpu, 3);
+        let first = cpu.step_instruction().unwrap();
+
+        // the copy starts at OAMADDR and wraps around
+        cpu.bus.write(0x2003, 0x10).unwrap();
+        assert_eq!(cpu.bus.read(0x2004).unwrap(), 0x00);
+        cpu.bus.write(0x2003, 0x0f).unwrap();
+        assert_eq!(cpu.bus.read(0x2004).unwrap(), 0xff);
+
+        // seven cycles later the second transfer starts on the other parity
+        cpu.step_instruction().unwrap();
+        let second = cpu.step_instruction().unwrap();
+        let mut stalls = vec![first - 4, second - 4];
+        stalls.sort_unstable();
+        assert_eq!(stalls, vec![513, 514]);
+    }
+
+    #[test]
+    fn frame_counter_restart_follows_the_write_cycle() {
+        // [LDA $00 | LDA #$00]; STA $4017; JMP to itself
+        let programs: [&[u8]; 2] = [
+            &[0xa5, 0x00, 0x8d, 0x17, 0x40, 0x4c, 0x05, 0x02],
+            &[0xa9, 0x00, 0x8d, 0x17, 0x40, 0x4c, 0x05, 0x02]
+        ];
+        let mut delays = Vec::new();
+        for program in programs {
+            let mut cpu = cpu_with_program(program);
+            run_instructions(&mut cpu, 2);
+            // the store's last cycle is the write
+            let written = cpu.cycles;
+            let apu_cycle = written - 1;
+            while !cpu.bus.apu.irq_line() {
+                cpu.step().unwrap();
+            }
+            delays.push((apu_cycle & 0x01, cpu.cycles - written));
+        }
+        // counting the write, the sequencer restarts on the third cycle when
+        // written on an even APU cycle and on the fourth otherwise, the IRQ
+        // is raised 29828 cycles after that
+        assert_eq!(delays, vec![(0, 2 + 29828), (1, 3 + 29828)]);
+    }
+
+    #[test]
+    fn dmc_fetch_stalls_cpu() {
+        // LDA #$0F; STA $4010; LDA #$10; STA $4015
+        let mut cpu = cpu_with_program(&[0xa9, 0x0f, 0x8d, 0x10, 0x40, 0xa9, 0x10, 0x8d, 0x15, 0x40]);
+        run_instructions(&mut cpu, 3);
+        // enabling the channel fetches straight away, the next cycle reads
+        assert_eq!(cpu.step_instruction().unwrap(), 4 + 4);
+        assert_eq!(cpu.bus.apu.dmc_dma_request(), None);
+    }
+
+    // flat memory with a controller at $4016 and a DMC fetch of $C000
+    // requested after a chosen CPU cycle
+    struct DmcTestBus {
+        ram: FlatRam,
+        controller: controller::Controller,
+        cycles: usize,
+        fetch_after: usize,
+        fetched: Option<u8>
+    }
+
+    impl Bus for DmcTestBus {
+        fn read(&mut self, addr: u16) -> Result<u8> {
+            match addr {
+                0x4016 => Ok(self.controller.read()),
+                _ => self.ram.read(addr)
+            }
+        }
+
+        fn write(&mut self, addr: u16, data: u8) -> Result<()> {
+            match addr {
+                0x4016 => {
+                    self.controller.write(data);
+                    Ok(())
+                },
+                _ => self.ram.write(addr, data)
+            }
+        }
+
+        fn tick(&mut self) {
+            self.cycles += 1;
+        }
+
+        fn poll_dmc_dma(&mut self) -> Option<u16> {
+            if self.fetched.is_none() && self.cycles == self.fetch_after {Some(0xc000)} else {None}
+        }
+
+        fn complete_dmc_dma(&mut self, data: u8) {
+            self.fetched = Some(data);
+        }
+    }
+
+    fn cpu_with_dmc_bus(program: &[u8], fetch_after: usize) -> CPU<DmcTestBus> {
+        let mut ram = FlatRam::new();
+        ram.load(0x0400, program).unwrap();
+        ram.load(0xc000, &[0x5a]).unwrap();
+        let mut cpu = CPU::new(DmcTestBus {
+            ram,
+            controller: controller::Controller::new(),
+            cycles: 0,
+            fetch_after,
+            fetched: None
+        });
+        cpu.r_pc = 0x0400;
+        cpu.r_sp = STACK_START;
+        cpu.r_st = STATUS_START;
+        cpu
+    }
+
+    #[test]
+    fn dmc_halt_waits_for_writes() {
+        // (instruction, cycle the fetch is requested after, total cycles)
+        let cases: [(&[u8], usize, usize); 5] = [
+            // LDA $0010, the next cycle reads
+            (&[0xad, 0x10, 0x00], 1, 4 + 4),
+            // STA $0010, the write goes ahead
+            (&[0x8d, 0x10, 0x00], 3, 4 + 3),
+            (&[0x8d, 0x10, 0x00], 4, 4 + 4),
+            // INC $0010, both writes go ahead
+            (&[0xee, 0x10, 0x00], 4, 6 + 2),
+            (&[0xee, 0x10, 0x00], 5, 6 + 3)
+        ];
+        for (program, fetch_after, cycles) in cases {
+            let mut cpu = cpu_with_dmc_bus(program, fetch_after);
+            cpu.r_a = 0x42;
+            assert_eq!(cpu.step_instruction().unwrap(), cycles, "{:02x?} after {}", program, fetch_after);
+            assert_eq!(cpu.bus.fetched, Some(0x5a));
+        }
+    }
+
+    #[test]
+    fn dmc_fetch_during_controller_read_skips_a_bit() {
+        // strobe, then LDA $4016 twice
+        let program = [
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40,
+            0xad, 0x16, 0x40, 0xad, 0x16, 0x40
+        ];
+        // the strobe takes 12 cycles, the first LDA reads on cycle 16
+        let mut results = Vec::new();
+        for fetch_after in [usize::MAX, 14, 15] {
+            let mut cpu = cpu_with_dmc_bus(&program, fetch_after);
+            cpu.bus.controller.set_buttons(controller::BUTTON_A | controller::BUTTON_SELECT);
+            for _ in 0..4 {
+                cpu.step_instruction().unwrap();
+            }
+            let cycles = cpu.step_instruction().unwrap();
+            let first = cpu.r_a & 0x01;
+            cpu.step_instruction().unwrap();
+            results.push((cycles, first, cpu.r_a & 0x01));
+        }
+        assert_eq!(results, vec![
+            (4, 1, 0),
+            // halting on an operand fetch only costs time
+            (4 + 4, 1, 0),
+            // halting on the read itself repeats it, A is lost and the
+            // reads see B and Select
+            (4 + 4, 0, 1)
+        ]);
+    }
+
+    #[test]
+    fn adc_sets_carry_and_overflow() {
+        // LDA #$50; ADC #$50; LDA #$FF; ADC #$01
+        let mut cpu = cpu_with_program(&[0xa9, 0x50, 0x69, 0x50, 0xa9, 0xff, 0x69, 0x01]);
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_a, 0xa0);
+        assert!(cpu.get_flag(STATUS_V));
+        assert!(cpu.get_flag(STATUS_N));
+        assert!(!cpu.get_flag(STATUS_C));
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_a, 0x00);
+        assert!(cpu.get_flag(STATUS_C));
+        assert!(cpu.get_flag(STATUS_Z));
+        assert!(!cpu.get_flag(STATUS_V));
+    }
+
+    #[test]
+    fn sbc_borrows_through_carry() {
+        // SEC; LDA #$50; SBC #$F0; SBC #$B0
+        let mut cpu = cpu_with_program(&[0x38, 0xa9, 0x50, 0xe9, 0xf0, 0xe9, 0xb0]);
+        run_instructions(&mut cpu, 3);
+        assert_eq!(cpu.r_a, 0x60);
+        assert!(!cpu.get_flag(STATUS_C));
+        assert!(!cpu.get_flag(STATUS_V));
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.r_a, 0xaf);
+        assert!(!cpu.get_flag(STATUS_C));
+        assert!(cpu.get_flag(STATUS_V));
+    }
+
+    #[test]
+    fn compare_and_branch() {
+        // LDX #$03; DEX; CPX #$01; BNE -5; STX $10
+        let mut cpu = cpu_with_program(&[0xa2, 0x03, 0xca, 0xe0, 0x01, 0xd0, 0xfb, 0x86, 0x10]);
+        run_instructions(&mut cpu, 8);
+        assert_eq!(cpu.r_x, 0x01);
+        assert!(cpu.get_flag(STATUS_Z));
+        assert!(cpu.get_flag(STATUS_C));
+        assert_eq!(cpu.bus.ram[0x10], 0x01);
+    }
+
+    #[test]
+    fn shifts_rotate_through_carry() {
+        // LDA #$81; ASL A; ROL A; LSR $10; ROR $10
+        let mut cpu = cpu_with_program(&[0xa9, 0x81, 0x0a, 0x2a, 0x46, 0x10, 0x66, 0x10]);
+        cpu.bus.ram[0x10] = 0x01;
+        run_instructions(&mut cpu, 3);
+        assert_eq!(cpu.r_a, 0x05);
+        assert!(!cpu.get_flag(STATUS_C));
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.bus.ram[0x10], 0x00);
+        assert!(cpu.get_flag(STATUS_C));
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.bus.ram[0x10], 0x80);
+        assert!(cpu.get_flag(STATUS_N));
+    }
+
+    #[test]
+    fn jsr_rts_and_stack() {
+        // JSR $0210; LDY #$01; ... $0210: PHP; LDA #$00; PLP; RTS
+        let mut cpu = cpu_with_program(&[0x20, 0x10, 0x02, 0xa0, 0x01]);
+        cpu.bus.ram[0x210..0x215].copy_from_slice(&[0x08, 0xa9, 0x00, 0x28, 0x60]);
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.r_pc, 0x0210);
+        assert_eq!(cpu.bus.ram[0x1fd], 0x02);
+        assert_eq!(cpu.bus.ram[0x1fc], 0x02);
+        run_instructions(&mut cpu, 3);
+        assert_eq!(cpu.bus.ram[0x1fb], STATUS_START | STATUS_B);
+        assert!(!cpu.get_flag(STATUS_Z));
+        assert!(!cpu.get_flag(STATUS_B));
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_y, 0x01);
+        assert_eq!(cpu.r_sp, STACK_START);
+    }
+
+    #[test]
+    fn bit_copies_high_bits() {
+        // LDA #$01; BIT $10
+        let mut cpu = cpu_with_program(&[0xa9, 0x01, 0x24, 0x10]);
+        cpu.bus.ram[0x10] = 0xc0;
+        run_instructions(&mut cpu, 2);
+        assert!(cpu.get_flag(STATUS_Z));
+        assert!(cpu.get_flag(STATUS_N));
+        assert!(cpu.get_flag(STATUS_V));
+    }
+
+    #[test]
+    fn zero_page_indexing_wraps() {
+        // LDX #$10; LDA $F8,X; LDA ($F8,X) with the pointer at $FF/$00
+        let mut cpu = cpu_with_program(&[0xa2, 0x10, 0xb5, 0xf8, 0xa2, 0x07, 0xa1, 0xf8]);
+        cpu.bus.ram[0x08] = 0x42;
+        cpu.bus.ram[0xff] = 0x34;
+        cpu.bus.ram[0x00] = 0x01;
+        cpu.bus.ram[0x134] = 0x99;
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_a, 0x42);
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_a, 0x99);
+    }
+
+    #[test]
+    fn indirect_y_reports_page_cross() {
+        // LDY #$10; LDA ($20),Y
+        let mut cpu = cpu_with_program(&[0xa0, 0x10, 0xb1, 0x20]);
+        cpu.bus.ram[0x20] = 0xf8;
+        cpu.bus.ram[0x21] = 0x00;
+        cpu.bus.ram[0x108] = 0x77;
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_a, 0x77);
+        assert!(cpu.page_crossed);
+    }
+
+    #[test]
+    fn indirect_jmp_page_wrap_bug() {
+        // JMP ($02FF) reads the high byte from $0200, not $0300
+        let mut cpu = cpu_with_program(&[0x6c, 0xff, 0x02]);
+        cpu.bus.ram[0x2ff] = 0x34;
+        cpu.bus.ram[0x300] = 0x12;
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.r_pc, 0x6c34);
+    }
+
+    #[test]
+    fn cycles_include_page_cross_and_branch_penalties() {
+        // LDX #$01; LDA $02FF,X; STA $0300,X; BNE +0; BEQ +0; JMP $02F0
+        let mut cpu = cpu_with_program(&[
+            0xa2, 0x01, 0xbd, 0xff, 0x02, 0x9d, 0x00, 0x03,
+            0xd0, 0x00, 0xf0, 0x00, 0x4c, 0xf0, 0x02
+        ]);
+        // $02F0: BNE +16, which lands on the next page
+        cpu.bus.ram[0x2f0..0x2f2].copy_from_slice(&[0xd0, 0x10]);
+        cpu.bus.ram[0x300] = 0x01;
+        let expected = [2, 5, 5, 3, 2, 3, 4];
+        for cycles in expected.iter() {
+            let start = cpu.cycles;
+            run_instructions(&mut cpu, 1);
+            assert_eq!(cpu.cycles - start, *cycles);
+        }
+        assert_eq!(cpu.r_pc, 0x0302);
+    }
+
+    #[test]
+    fn unofficial_read_modify_write() {
+        // LAX $10; SLO $11; DCP $12; ISC $13
+        let mut cpu = cpu_with_program(&[0xa7, 0x10, 0x07, 0x11, 0xc7, 0x12, 0xe7, 0x13]);
+        cpu.bus.ram[0x10..0x14].copy_from_slice(&[0x81, 0x40, 0x82, 0x00]);
+        run_instructions(&mut cpu, 1);
+        assert_eq!((cpu.r_a, cpu.r_x), (0x81, 0x81));
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.bus.ram[0x11], 0x80);
+        assert_eq!(cpu.r_a, 0x81);
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.bus.ram[0x12], 0x81);
+        assert!(cpu.get_flag(STATUS_Z));
+        assert!(cpu.get_flag(STATUS_C));
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.bus.ram[0x13], 0x01);
+        assert_eq!(cpu.r_a, 0x80);
+    }
+
+    #[test]
+    fn unofficial_nops_consume_operands() {
+        // NOP $1234,X; NOP #$00; NOP (1 byte)
+        let mut cpu = cpu_with_program(&[0x1c, 0x34, 0x12, 0x80, 0x00, 0x1a]);
+        run_instructions(&mut cpu, 3);
+        assert_eq!(cpu.r_pc, 0x0206);
+    }
+
+    #[test]
+    fn strict_mode_rejects_unofficial_opcodes() {
+        let mut cpu = cpu_with_program(&[0xea, 0x1a]);
+        cpu.set_illegal_opcodes(false);
+        run_instructions(&mut cpu, 1);
+        assert!(cpu.step().is_err());
+    }
+
+    #[test]
+    fn nmi_pushes_state_and_vectors() {
+        let mut cpu = cpu_with_program(&[0xea]);
+        cpu.interrupt(InteruptType::NMI);
+        let start = cpu.cycles;
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.cycles - start, 7);
+        assert_eq!(cpu.r_pc, 0x0400);
+        assert_eq!(&cpu.bus.ram[0x1fb..0x1fe], &[STATUS_START, 0x00, 0x02]);
+        assert!(cpu.get_flag(STATUS_I));
+    }
+
+    #[test]
+    fn irq_is_masked_and_level_triggered() {
+        // NOP; CLI; NOP; NOP, with RTI at the IRQ handler
+        let mut cpu = cpu_with_program(&[0xea, 0x58, 0xea, 0xea]);
+        cpu.bus.ram[0x500] = 0x40;
+        cpu.set_irq_line(true);
+        run_instructions(&mut cpu, 2);
+        // the IRQ is recognised one instruction after CLI
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.r_pc, 0x0203);
+        run_instructions(&mut cpu, 1);
+        assert_eq!(cpu.r_pc, 0x0500);
+        assert_eq!(cpu.bus.ram[0x1fb] & STATUS_B, 0);
+        // still asserted after RTI, so it fires again
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_pc, 0x0500);
+        cpu.set_irq_line(false);
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_pc, 0x0204);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        // BRK, with LDA #$42 at the NMI handler
+        let mut cpu = cpu_with_program(&[0x00, 0x00]);
+        cpu.bus.ram[0x400..0x402].copy_from_slice(&[0xa9, 0x42]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.interrupt(InteruptType::NMI);
+        // finish the BRK sequence, then run the first handler instruction
+        run_instructions(&mut cpu, 2);
+        assert_eq!(cpu.r_a, 0x42);
+        assert_eq!(cpu.bus.ram[0x1fb], STATUS_START | STATUS_B);
+        assert_eq!(&cpu.bus.ram[0x1fc..0x1fe], &[0x02, 0x02]);
+        assert!(!cpu.nmi_pending);
+    }
+
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_matches_nestest_format() {
+        // LDX #$02; STX $10; LDA ($0E,X); ISC $10; ASL A
+        let mut cpu = cpu_with_program(&[0xa2, 0x02, 0x86, 0x10, 0xa1, 0x0e, 0xe7, 0x10, 0x0a]);
+        cpu.bus.ram[0x11] = 0x03;
+        cpu.bus.ram[0x302] = 0x5a;
+        let out = Rc::new(RefCell::new(Vec::new()));
+        cpu.set_tracer(Box::new(SharedBuf(out.clone())));
+        run_instructions(&mut cpu, 5);
+        let log = String::from_utf8(out.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines, vec![
+            "0200  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+            "0202  86 10     STX $10 = 00                    A:00 X:02 Y:00 P:24 SP:FD PPU:  0,  0 CYC:2",
+            "0204  A1 0E     LDA ($0E,X) @ 10 = 0302 = 5A    A:00 X:02 Y:00 P:24 SP:FD PPU:  0,  0 CYC:5",
+            "0206  E7 10    *ISB $10 = 02                    A:5A X:02 Y:00 P:24 SP:FD PPU:  0,  0 CYC:11",
+            "0208  0A        ASL A                           A:56 X:02 Y:00 P:25 SP:FD PPU:  0,  0 CYC:16",
+        ]);
+    }
+
+    // runs until the program jumps to itself, the way Klaus Dormann's
+    // functional test reports success or failure
+    fn run_until_trap(cpu: &mut CPU<FlatRam>, limit: usize) -> u16 {
+        for _ in 0..limit {
+            let pc = cpu.pc();
+            cpu.step_instruction().unwrap();
+            if cpu.pc() == pc {
+                return pc;
+            }
+        }
+        panic!("no trap after {} instructions", limit);
+    }
+
+    #[test]
+    fn runs_standalone_on_flat_ram() {
+        // sum 1..=10 into $10, then trap at $0410 on success
+        let program = [
+            0xa2, 0x0a, 0xa9, 0x00, 0x18, 0x86, 0x11, 0x65, 0x11, 0xca,
+            0xd0, 0xf9, 0x85, 0x10, 0xc9, 0x37, 0xf0, 0x02, 0xd0, 0xfe,
+            0x4c, 0x14, 0x04
+        ];
+        let mut ram = FlatRam::new();
+        ram.load(0x0400, &program).unwrap();
+        let mut cpu = CPU::new(ram);
+        cpu.set_pc(0x0400);
+        assert_eq!(run_until_trap(&mut cpu, 1000), 0x0414);
+        assert_eq!(cpu.bus().peek(0x10), Some(55));
+    }
+
+    #[test]
+    fn decimal_mode_is_opt_in() {
+        // SED; CLC; LDA #$19; ADC #$28; SEC; SBC #$09
+        let program = [0xf8, 0x18, 0xa9, 0x19, 0x69, 0x28, 0x38, 0xe9, 0x09];
+        let mut ram = FlatRam::new();
+        ram.load(0x0400, &program).unwrap();
+        let mut cpu = CPU::new(ram);
+        cpu.set_pc(0x0400);
+        for _ in 0..4 {
+            cpu.step_instruction().unwrap();
+        }
+        assert_eq!(cpu.r_a, 0x41);
+
+        let mut ram = FlatRam::new();
+        ram.load(0x0400, &program).unwrap();
+        let mut cpu = CPU::new(ram);
+        cpu.set_decimal_mode(true);
+        cpu.set_pc(0x0400);
+        for _ in 0..4 {
+            cpu.step_instruction().unwrap();
+        }
+        assert_eq!(cpu.r_a, 0x47);
+        assert!(!cpu.get_flag(STATUS_C));
+        for _ in 0..2 {
+            cpu.step_instruction().unwrap();
+        }
+        assert_eq!(cpu.r_a, 0x38);
+        assert!(cpu.get_flag(STATUS_C));
+    }
+}
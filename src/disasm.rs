@@ -0,0 +1,172 @@
+use std::fmt;
+use crate::cpu::OP_MAP;
+pub use crate::cpu::{AddressMode, Instructions};
+
+
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    // None when the byte is not a known opcode, shown as `.db`
+    pub mnemonic: Option<Instructions>,
+    pub mode: AddressMode,
+    pub operand: String,
+    // resolved destination of branches and absolute JMP/JSR
+    pub target: Option<u16>,
+    pub unofficial: bool
+}
+
+impl Instruction {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn hex_bytes(&self) -> String {
+        let hex: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        hex.join(" ")
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if self.unofficial {'*'} else {' '};
+        let text = match self.mnemonic {
+            Some(ins) => format!("{}{:?} {}", prefix, ins, self.operand),
+            None => format!(" .db ${:02X}", self.bytes[0])
+        };
+        write!(f, "{:04X}  {:<8} {}", self.addr, self.hex_bytes(), text.trim_end())
+    }
+}
+
+// decodes the instruction at `addr`, reading memory through `peek`
+pub fn decode<F>(peek: F, addr: u16) -> Instruction
+    where F: Fn(u16) -> u8 {
+    let opcode = peek(addr);
+    let op = match OP_MAP.get(&opcode) {
+        Some(op) => op,
+        None => return data_byte(addr, opcode)
+    };
+    let bytes: Vec<u8> = (0..op.2 as u16).map(|i| peek(addr.wrapping_add(i))).collect();
+    let lo = bytes.get(1).copied().unwrap_or(0);
+    let abs = (bytes.get(2).copied().unwrap_or(0) as u16) << 8 | lo as u16;
+    let mut target = None;
+    let operand = match op.1 {
+        AddressMode::IMP => match op.0 {
+            Instructions::ASL | Instructions::LSR |
+            Instructions::ROL | Instructions::ROR => "A".to_string(),
+            _ => String::new()
+        },
+        AddressMode::IMM => format!("#${:02X}", lo),
+        AddressMode::ZP  => format!("${:02X}", lo),
+        AddressMode::ZPX => format!("${:02X},X", lo),
+        AddressMode::ZPY => format!("${:02X},Y", lo),
+        AddressMode::IZX => format!("(${:02X},X)", lo),
+        AddressMode::IZY => format!("(${:02X}),Y", lo),
+        AddressMode::ABS => {
+            if op.0 == Instructions::JMP || op.0 == Instructions::JSR {
+                target = Some(abs);
+            }
+            format!("${:04X}", abs)
+        },
+        AddressMode::ABX => format!("${:04X},X", abs),
+        AddressMode::ABY => format!("${:04X},Y", abs),
+        AddressMode::IND => format!("(${:04X})", abs),
+        AddressMode::REL => {
+            let dest = addr.wrapping_add(2).wrapping_add(lo as i8 as u16);
+            target = Some(dest);
+            format!("${:04X}", dest)
+        }
+    };
+    Instruction {
+        addr,
+        bytes,
+        mnemonic: Some(op.0),
+        mode: op.1,
+        operand,
+        target,
+        unofficial: op.0.is_unofficial(opcode)
+    }
+}
+
+fn data_byte(addr: u16, data: u8) -> Instruction {
+    Instruction {
+        addr,
+        bytes: vec![data],
+        mnemonic: None,
+        mode: AddressMode::IMP,
+        operand: String::new(),
+        target: None,
+        unofficial: false
+    }
+}
+
+// disassembles `bytes` as if they were mapped at `origin`; a trailing
+// instruction cut short by the end of the slice is emitted as data
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = origin.wrapping_add(offset as u16);
+        let mut ins = decode(|a| bytes.get(a.wrapping_sub(origin) as usize).copied().unwrap_or(0), addr);
+        if offset + ins.len() > bytes.len() {
+            ins = data_byte(addr, bytes[offset]);
+        }
+        offset += ins.len();
+        result.push(ins);
+    }
+    result
+}
+
+// disassembles the address range [start, end) read through `peek`
+pub fn disassemble_range<F>(peek: F, start: u16, end: u16) -> Vec<Instruction>
+    where F: Fn(u16) -> u8 {
+    let mut result = Vec::new();
+    let mut addr = start as u32;
+    while addr < end as u32 {
+        let ins = decode(&peek, addr as u16);
+        addr += ins.len() as u32;
+        result.push(ins);
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_each_address_mode() {
+        let code = [
+            0xa9, 0x01, 0xb5, 0x10, 0xa1, 0x20, 0xb1, 0x30,
+            0xbd, 0x00, 0x03, 0x6c, 0xff, 0x02, 0x0a, 0xd0, 0xfe
+        ];
+        let text: Vec<String> = disassemble(&code, 0xc000).iter().map(|i| i.to_string()).collect();
+        assert_eq!(text, vec![
+            "C000  A9 01     LDA #$01",
+            "C002  B5 10     LDA $10,X",
+            "C004  A1 20     LDA ($20,X)",
+            "C006  B1 30     LDA ($30),Y",
+            "C008  BD 00 03  LDA $0300,X",
+            "C00B  6C FF 02  JMP ($02FF)",
+            "C00E  0A        ASL A",
+            "C00F  D0 FE     BNE $C00F",
+        ]);
+    }
+
+    #[test]
+    fn resolves_targets_and_flags_data() {
+        let code = [0x20, 0x34, 0x12, 0x10, 0x80, 0x02, 0x07, 0x10, 0xad];
+        let listing = disassemble(&code, 0x8000);
+        assert_eq!(listing[0].target, Some(0x1234));
+        assert_eq!(listing[1].target, Some(0x7f85));
+        assert_eq!(listing[2].mnemonic, None);
+        assert_eq!(listing[2].to_string(), "8005  02        .db $02");
+        assert!(listing[3].unofficial);
+        assert_eq!(listing[3].to_string(), "8006  07 10    *SLO $10");
+        assert_eq!(listing[4].to_string(), "8008  AD        .db $AD");
+    }
+}
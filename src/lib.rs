@@ -3,15 +3,22 @@ extern crate pretty_env_logger;
 #[macro_use] extern crate log;
 use wasm_bindgen::prelude::*;
 use anyhow::{anyhow, Result};
-use std::cell::RefCell;
-use std::rc::Rc;
 #[macro_use]
 extern crate lazy_static;
 
-mod cpu;
+pub mod cpu;
+pub mod apu;
 mod ppu;
 mod cart;
 mod utils;
+pub mod bus;
+pub mod clock;
+pub mod controller;
+pub mod disasm;
+pub mod palette;
+pub mod audio;
+pub mod wav;
+pub mod nsf;
 
 #[wasm_bindgen]
 extern {
@@ -26,62 +33,387 @@ pub fn greet(name: &str) {
 }
 
 #[wasm_bindgen]
-struct Emu {
-    cart: Rc<RefCell<cart::Cartridge>>,
-    cpu: Rc<RefCell<cpu::CPU>>,
-    ppu: Rc<RefCell<ppu::PPU>>,
-    frame: Rc<RefCell<Vec<u8>>>,
-    cycles: usize
+pub struct Emu {
+    cpu: cpu::CPU,
+    palette: palette::Palette,
+    frame_format: palette::FrameFormat,
+    rgba: Vec<u8>,
+    recorder: Option<wav::WavRecorder>,
+    // changes that would break the files being recorded wait for
+    // stop_recording
+    pending_sample_rate: Option<u32>,
+    pending_channel_capture: Option<bool>
 }
 
 impl Emu {
     pub fn new() -> Self {
-        let frame = Rc::new(RefCell::new(Vec::with_capacity(240*256)));
-        let cart_rc = Rc::new(RefCell::new(cart::Cartridge::new()));
-        let ppu_rc = Rc::new(RefCell::new(ppu::PPU::new(frame.clone()))) ;
-        let cpu_rc = Rc::new(RefCell::new(cpu::CPU::new(cart_rc.clone(), ppu_rc.clone())));
-        ppu_rc.borrow_mut().set_vblank_cb(|| cpu_rc.borrow_mut().interrupt(cpu::InteruptType::NMI));
+        let bus = bus::SystemBus::new(cart::Cartridge::new());
         Emu {
-            cart: cart_rc.clone(),
-            cpu: cpu_rc.clone(),
-            ppu: ppu_rc.clone(),
-            frame: frame.clone(),
-            cycles: 0
+            cpu: cpu::CPU::new(bus),
+            palette: palette::Palette::ntsc(),
+            frame_format: palette::FrameFormat::Indexed,
+            rgba: Vec::new(),
+            recorder: None,
+            pending_sample_rate: None,
+            pending_channel_capture: None
         }
     }
 
+    pub fn set_region(&mut self, region: clock::Region) {
+        self.cpu.bus_mut().set_region(region);
+    }
+
+    pub fn region(&self) -> clock::Region {
+        self.cpu.bus().clock.region()
+    }
+
     pub fn load_rom(&mut self, path: &str) -> Result<()>{
-        self.cart.borrow_mut().load_from_file(path)
+        self.cpu.bus_mut().cart.load_from_file(path)
     }
 
     pub fn init(&mut self) -> Result<()> {
-        self.cpu.borrow_mut().reset()?;
-        self.ppu.borrow_mut().reset()?;
+        if !self.cpu.bus().cart.is_loaded() {
+            return Err(anyhow!("no ROM loaded"));
+        }
+        self.cpu.bus_mut().clock.reset();
+        self.cpu.reset()?;
+        self.cpu.bus_mut().ppu.reset()?;
+        Ok(())
+    }
+
+    // buttons use the controller::BUTTON_* bits, port is 0 or 1
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) -> Result<()> {
+        let controller = self.cpu.bus_mut().controllers.get_mut(port)
+            .ok_or_else(|| anyhow!("no controller port {}", port))?;
+        controller.set_buttons(buttons);
         Ok(())
     }
 
-    pub fn tick(&mut self) {
-        if self.cycles % 4 == 0 {
-            self.cpu.borrow_mut().step();
-        }else{
-            self.ppu.borrow_mut().step();
+    // runs whichever of the CPU and PPU owns the next master clock cycle
+    pub fn tick(&mut self) -> Result<()> {
+        match self.cpu.bus_mut().clock.advance() {
+            clock::Component::CPU => self.cpu.step(),
+            clock::Component::PPU => self.cpu.bus_mut().step_ppu()
+        }
+    }
+
+    // runs a whole CPU instruction while the PPU stays behind, saving the
+    // switch between components on every cycle. The bus catches the PPU
+    // up before PPU register and $4014 accesses and before an NMI could
+    // be seen, so the result matches tick(). Returns true when a frame
+    // was finished, which ends the audio frame like frame() does.
+    pub fn step_instruction(&mut self) -> Result<bool> {
+        self.cpu.bus_mut().begin_catch_up();
+        let stepped = self.cpu.step_instruction();
+        let synced = self.cpu.bus_mut().end_catch_up();
+        stepped?;
+        synced?;
+        if !self.cpu.bus_mut().ppu.poll_frame_complete() {
+            return Ok(false);
+        }
+        self.end_frame()?;
+        Ok(true)
+    }
+
+    fn end_frame(&mut self) -> Result<()> {
+        self.cpu.bus_mut().apu.end_audio_frame();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.write_pending(&mut self.cpu.bus_mut().apu)?;
+        }
+        Ok(())
+    }
+
+    // what frame() returns, 256x240 palette indices or 256x240x4 RGBA
+    pub fn set_frame_format(&mut self, format: palette::FrameFormat) {
+        self.frame_format = format;
+    }
+
+    // used for RGBA frames, see palette::Palette for loading and exporting
+    pub fn set_palette(&mut self, palette: palette::Palette) {
+        self.palette = palette;
+    }
+
+    pub fn palette(&self) -> &palette::Palette {
+        &self.palette
+    }
+
+    // host sample rate of the audio output, 44100 by default. While
+    // recording it only changes once the recording stops.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if self.is_recording() {
+            self.pending_sample_rate = Some(sample_rate);
+            return;
+        }
+        self.cpu.bus_mut().apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.cpu.bus().apu.audio().sample_rate()
+    }
+
+    // audio produced since the last call, frame() makes everything up to
+    // the end of the frame available
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        let audio = self.cpu.bus_mut().apu.audio_mut();
+        let mut out = vec![0.0; audio.samples_available()];
+        audio.read_samples(&mut out);
+        out
+    }
+
+    pub fn audio_samples_i16(&mut self) -> Vec<i16> {
+        let audio = self.cpu.bus_mut().apu.audio_mut();
+        let mut out = vec![0; audio.samples_available()];
+        audio.read_samples_i16(&mut out);
+        out
+    }
+
+    // records the audio of the following frames to a .wav file at the
+    // current sample rate, with `stems` every channel also gets a file of
+    // its own, see wav::WavRecorder
+    pub fn start_recording(&mut self, path: &str, stems: bool) -> Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(wav::WavRecorder::start(&mut self.cpu.bus_mut().apu, path, stems)?);
+        Ok(())
+    }
+
+    // finishes the files after the last frame run and applies the
+    // sample rate and channel capture changes made meanwhile
+    pub fn stop_recording(&mut self) -> Result<()> {
+        let stopped = match self.recorder.take() {
+            Some(recorder) => recorder.stop(&mut self.cpu.bus_mut().apu),
+            None => Ok(())
+        };
+        if let Some(sample_rate) = self.pending_sample_rate.take() {
+            self.set_sample_rate(sample_rate);
+        }
+        if let Some(capture) = self.pending_channel_capture.take() {
+            self.set_channel_capture(capture);
+        }
+        stopped
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // runs until the PPU enters vblank and returns the finished frame
+    pub fn frame(&mut self) -> Result<&[u8]> {
+        loop {
+            self.tick()?;
+            if self.cpu.bus_mut().ppu.poll_frame_complete() {
+                break;
+            }
+        }
+        self.end_frame()?;
+        let ppu = &self.cpu.bus().ppu;
+        match self.frame_format {
+            palette::FrameFormat::Indexed => Ok(ppu.frame()),
+            palette::FrameFormat::RGBA => {
+                self.palette.write_rgba(ppu.frame(), ppu.frame_emphasis(), &mut self.rgba);
+                Ok(&self.rgba)
+            }
+        }
+    }
+}
+
+// audio channel controls, also exported to JavaScript
+#[wasm_bindgen]
+impl Emu {
+    pub fn set_channel_enabled(&mut self, channel: apu::Channel, enabled: bool) {
+        self.cpu.bus_mut().apu.set_channel_enabled(channel, enabled);
+    }
+
+    pub fn set_channel_gain(&mut self, channel: apu::Channel, gain: f32) {
+        self.cpu.bus_mut().apu.set_channel_gain(channel, gain);
+    }
+
+    // mixes only `channel` until clear_solo
+    pub fn solo_channel(&mut self, channel: apu::Channel) {
+        self.cpu.bus_mut().apu.set_solo(Some(channel));
+    }
+
+    pub fn clear_solo(&mut self) {
+        self.cpu.bus_mut().apu.set_solo(None);
+    }
+
+    // starts or stops recording every channel's pre-mix output on its
+    // own. While recording it only changes once the recording stops.
+    pub fn set_channel_capture(&mut self, capture: bool) {
+        if self.is_recording() {
+            self.pending_channel_capture = Some(capture);
+            return;
         }
-        self.cycles += 1;
+        self.cpu.bus_mut().apu.set_channel_capture(capture);
     }
 
-    pub fn frame(&mut self) -> Result<Rc<RefCell<Vec<u8>>>> {
-        while !self.cpu.borrow().is_frame_ready() {
-            self.tick();
+    // captured audio of `channel` since the last call, empty when not
+    // capturing
+    pub fn channel_samples(&mut self, channel: apu::Channel) -> Vec<f32> {
+        match self.cpu.bus_mut().apu.channel_audio_mut(channel) {
+            Some(audio) => {
+                let mut out = vec![0.0; audio.samples_available()];
+                audio.read_samples(&mut out);
+                out
+            },
+            None => Vec::new()
         }
-        return Ok(self.frame.clone())
+    }
+}
+
+impl Default for Emu {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    // NROM image running `program` from $8000, with `nmi` at $8200
+    fn test_rom(program: &[u8], nmi: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x00, 0x00];
+        rom.resize(16, 0);
+        let mut prg = vec![0xeau8; 0x8000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x200..0x200 + nmi.len()].copy_from_slice(nmi);
+        prg[0x7ffa..].copy_from_slice(&[0x00, 0x82, 0x00, 0x80, 0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0u8; 0x2000]);
+        rom
+    }
+
+    // runs LDA $2002 so that its read cycle sees `dot` of the vblank line,
+    // returning the vblank bit read and the NMIs taken around it
+    fn read_status_at(dot: u16) -> (u8, u8) {
+        // enable NMI, then idle; $8100: LDA $2002; STA $10; JMP $8005.
+        // The handler counts NMIs in $11.
+        let mut program = vec![0xa9, 0x80, 0x8d, 0x00, 0x20, 0xea, 0xea, 0xea, 0x4c, 0x05, 0x80];
+        program.resize(0x100, 0xea);
+        program.extend([0xad, 0x02, 0x20, 0x85, 0x10, 0x4c, 0x05, 0x80]);
+        let mut emu = Emu::new();
+        emu.cpu.bus_mut().cart.load_from_bytes(&test_rom(&program, &[0xe6, 0x11, 0x40])).unwrap();
+        emu.init().unwrap();
+
+        // the read is the fourth cycle, nine dots after the opcode fetch
+        let fetch_at = (240, 341 + dot - 9);
+        loop {
+            let cpu_next = !emu.cpu.bus().clock.ppu_due();
+            if cpu_next && emu.cpu.at_instruction_boundary() && emu.cpu.pc() >= 0x8005
+                && emu.cpu.pc() < 0x8100 && emu.cpu.bus().ppu.position() == fetch_at {
+                break;
+            }
+            emu.tick().unwrap();
+        }
+        let nmis = emu.cpu.bus().ram[0x11];
+        emu.cpu.set_pc(0x8100);
+        while emu.cpu.bus().ppu.position() != (243, 0) {
+            emu.tick().unwrap();
+        }
+        let ram = &emu.cpu.bus().ram;
+        (ram[0x10] & 0x80, ram[0x11].wrapping_sub(nmis))
+    }
+
+    #[test]
+    fn vblank_race_is_decided_on_the_read_cycle() {
+        assert_eq!(read_status_at(0), (0x00, 1));
+        // one dot early the flag and the NMI are lost for the frame
+        assert_eq!(read_status_at(1), (0x00, 0));
+        // on the dot it goes up, or the one after, only the NMI is lost
+        assert_eq!(read_status_at(2), (0x80, 0));
+        assert_eq!(read_status_at(3), (0x80, 0));
+        assert_eq!(read_status_at(4), (0x80, 1));
+    }
+
+    #[test]
+    fn step_instruction_matches_tick() {
+        // NMI and rendering on, then poll $2002 in a loop while the
+        // handler scrolls, reads $2002 and starts OAM DMA
+        let program = [
+            0xa9, 0x80, 0x8d, 0x00, 0x20, 0xa9, 0x1e, 0x8d, 0x01, 0x20,
+            0xad, 0x02, 0x20, 0xe6, 0x12, 0x2c, 0x02, 0x20, 0x85, 0x13, 0x4c, 0x0a, 0x80
+        ];
+        let nmi = [
+            0xe6, 0x11, 0xa9, 0x00, 0x8d, 0x05, 0x20, 0x8d, 0x05, 0x20,
+            0xad, 0x02, 0x20, 0x85, 0x14, 0xa9, 0x02, 0x8d, 0x14, 0x40, 0x40
+        ];
+        let rom = test_rom(&program, &nmi);
+        let mut emus = [Emu::new(), Emu::new()];
+        for emu in emus.iter_mut() {
+            emu.cpu.bus_mut().cart.load_from_bytes(&rom).unwrap();
+            emu.init().unwrap();
+        }
+        let [mut ticked, mut stepped] = emus;
+        let end = 3 * 29781;
+        let mut frames = (0, 0);
+        loop {
+            ticked.tick().unwrap();
+            if ticked.cpu.bus_mut().ppu.poll_frame_complete() {
+                frames.0 += 1;
+            }
+            if ticked.cpu.cycles() >= end && ticked.cpu.at_instruction_boundary() {
+                break;
+            }
+        }
+        // step_instruction leaves the PPU right before the next CPU cycle
+        while ticked.cpu.bus().clock.ppu_due() {
+            ticked.tick().unwrap();
+        }
+        while stepped.cpu.cycles() < end {
+            if stepped.step_instruction().unwrap() {
+                frames.1 += 1;
+            }
+        }
+
+        assert_eq!(frames, (3, 3));
+        assert_eq!(stepped.cpu.cycles(), ticked.cpu.cycles());
+        assert_eq!(stepped.cpu.pc(), ticked.cpu.pc());
+        let (a, b) = (stepped.cpu.bus(), ticked.cpu.bus());
+        assert_eq!(a.ram, b.ram);
+        assert_eq!(a.ppu.position(), b.ppu.position());
+        assert!(a.ppu.frame() == b.ppu.frame());
+        assert_eq!(a.clock.cycles(), b.clock.cycles());
+    }
+
+    #[test]
+    fn recording_holds_back_rate_and_capture_changes() {
+        let dir = std::env::temp_dir().join(format!("nes-lib-emu-rec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("take.wav").to_string_lossy().into_owned();
+
+        let mut emu = Emu::new();
+        emu.set_channel_capture(true);
+        emu.start_recording(&path, true).unwrap();
+        emu.set_channel_capture(false);
+        emu.set_sample_rate(22_050);
+        // the stems keep their buffers and the header its rate
+        assert!(emu.cpu.bus().apu.channel_capture());
+        assert_eq!(emu.sample_rate(), 44_100);
+
+        emu.stop_recording().unwrap();
+        assert!(!emu.cpu.bus().apu.channel_capture());
+        assert_eq!(emu.sample_rate(), 22_050);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn buttons_only_exist_on_two_ports() {
+        let mut emu = Emu::new();
+        emu.set_buttons(1, controller::BUTTON_A).unwrap();
+        emu.cpu.bus_mut().controllers[1].write(1);
+        assert_eq!(emu.cpu.bus().controllers[1].peek(), 0x41);
+        assert!(emu.set_buttons(2, controller::BUTTON_A).is_err());
+    }
+
+    #[test]
+    fn init_needs_a_rom() {
+        let mut emu = Emu::new();
+        assert!(emu.init().is_err());
+    }
 }
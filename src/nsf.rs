@@ -0,0 +1,611 @@
+// .nsf and .nsfe music files, played on the CPU and APU without a cartridge
+use std::fs;
+use anyhow::{anyhow, Result};
+use crate::apu;
+use crate::bus::Bus;
+use crate::clock::Region;
+use crate::cpu::CPU;
+
+// expansion sound chips, bits of the header's expansion byte. Only the
+// FDS memory layout is provided, none of the chips' audio is emulated.
+pub static EXPANSION_VRC6: u8 = 0x01;
+pub static EXPANSION_VRC7: u8 = 0x02;
+pub static EXPANSION_FDS: u8 = 0x04;
+pub static EXPANSION_MMC5: u8 = 0x08;
+pub static EXPANSION_N163: u8 = 0x10;
+pub static EXPANSION_5B: u8 = 0x20;
+
+// play periods in microseconds used when a file leaves them out
+static DEFAULT_NTSC_SPEED: u16 = 16639;
+static DEFAULT_PAL_SPEED: u16 = 19997;
+
+// the player idles in `JMP $4100` between calls, INIT and PLAY return there
+static IDLE_ADDR: u16 = 0x4100;
+static IDLE_LOOP: [u8; 3] = [0x4C, 0x00, 0x41];
+static BANK_SIZE: usize = 0x1000;
+
+// a parsed .nsf or .nsfe file
+#[derive(Default, Debug)]
+pub struct Nsf {
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    pub songs: u8,
+    // zero based
+    pub start_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    // PLAY periods in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // initial values of $5FF8-$5FFF, None when the tune is not bankswitched
+    pub banks: Option<[u8; 8]>,
+    // bit 0 PAL, bit 1 both regions
+    pub region_flags: u8,
+    pub expansion: u8,
+    pub data: Vec<u8>,
+    // NSFe metadata, empty when the file has none
+    pub track_labels: Vec<String>,
+    // lengths and fade outs in milliseconds, negative when unknown
+    pub track_times: Vec<i32>,
+    pub track_fades: Vec<i32>,
+    pub playlist: Vec<u8>
+}
+
+impl Nsf {
+    pub fn load_from_file(path: &str) -> Result<Self> {
+        Nsf::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.starts_with(b"NESM\x1A") {
+            Nsf::parse_nsf(data)
+        } else if data.starts_with(b"NSFE") {
+            let mut nsf = Nsf {
+                songs: 1,
+                ntsc_speed: DEFAULT_NTSC_SPEED,
+                pal_speed: DEFAULT_PAL_SPEED,
+                ..Nsf::default()
+            };
+            nsf.parse_chunks(&data[4..], true)?;
+            Ok(nsf)
+        } else {
+            Err(anyhow!("not an NSF or NSFe file"))
+        }
+    }
+
+    // 128 byte header followed by the program data. NSF2 files may append
+    // NSFe chunks after the data, its length is then given at $7D.
+    fn parse_nsf(data: &[u8]) -> Result<Self> {
+        if data.len() < 0x80 {
+            return Err(anyhow!("NSF header is truncated"));
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let mut banks = [0; 8];
+        banks.copy_from_slice(&data[0x70..0x78]);
+        let mut nsf = Nsf {
+            name: c_string(&data[0x0E..0x2E]),
+            artist: c_string(&data[0x2E..0x4E]),
+            copyright: c_string(&data[0x4E..0x6E]),
+            songs: data[0x06],
+            start_song: data[0x07].saturating_sub(1),
+            load_addr: word(0x08),
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            ntsc_speed: word(0x6E),
+            pal_speed: word(0x78),
+            banks: if banks.iter().any(|bank| *bank != 0) {Some(banks)} else {None},
+            region_flags: data[0x7A],
+            expansion: data[0x7B],
+            ..Nsf::default()
+        };
+        let length = u32::from_le_bytes([data[0x7D], data[0x7E], data[0x7F], 0]) as usize;
+        if data[0x05] >= 2 && length != 0 {
+            let end = (0x80 + length).min(data.len());
+            nsf.data = data[0x80..end].to_vec();
+            nsf.parse_chunks(&data[end..], false)?;
+        } else {
+            nsf.data = data[0x80..].to_vec();
+        }
+        if nsf.ntsc_speed == 0 {
+            nsf.ntsc_speed = DEFAULT_NTSC_SPEED;
+        }
+        if nsf.pal_speed == 0 {
+            nsf.pal_speed = DEFAULT_PAL_SPEED;
+        }
+        Ok(nsf)
+    }
+
+    // NSFe chunks are a length, a four letter id and the payload. Ids
+    // starting with a capital letter must be understood to play the file.
+    // `full` is false for the metadata tail of an NSF2 file.
+    fn parse_chunks(&mut self, mut data: &[u8], full: bool) -> Result<()> {
+        let mut has_info = !full;
+        while data.len() >= 8 {
+            let length = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let id = &data[4..8];
+            if data.len() < 8 + length {
+                return Err(anyhow!("NSFe chunk {} is truncated", String::from_utf8_lossy(id)));
+            }
+            let chunk = &data[8..8 + length];
+            data = &data[8 + length..];
+            let word = |offset: usize| chunk.get(offset..offset + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+            match id {
+                b"INFO" if full => {
+                    if chunk.len() < 9 {
+                        return Err(anyhow!("NSFe INFO chunk is too short"));
+                    }
+                    self.load_addr = word(0).unwrap_or(0);
+                    self.init_addr = word(2).unwrap_or(0);
+                    self.play_addr = word(4).unwrap_or(0);
+                    self.region_flags = chunk[6];
+                    self.expansion = chunk[7];
+                    self.songs = chunk.get(8).copied().unwrap_or(1);
+                    self.start_song = chunk.get(9).copied().unwrap_or(0);
+                    has_info = true;
+                },
+                b"DATA" if full => self.data = chunk.to_vec(),
+                b"BANK" if full => {
+                    let mut banks = [0; 8];
+                    for (bank, data) in banks.iter_mut().zip(chunk) {
+                        *bank = *data;
+                    }
+                    self.banks = Some(banks);
+                },
+                b"RATE" => {
+                    self.ntsc_speed = word(0).unwrap_or(self.ntsc_speed);
+                    self.pal_speed = word(2).unwrap_or(self.pal_speed);
+                },
+                b"NEND" => break,
+                b"auth" => {
+                    let mut fields = chunk.split(|byte| *byte == 0).map(c_string);
+                    self.name = fields.next().unwrap_or_default();
+                    self.artist = fields.next().unwrap_or_default();
+                    self.copyright = fields.next().unwrap_or_default();
+                    self.ripper = fields.next().unwrap_or_default();
+                },
+                b"tlbl" => {
+                    self.track_labels = chunk.split(|byte| *byte == 0).map(c_string).collect();
+                    self.track_labels.truncate(self.songs as usize);
+                },
+                b"time" => self.track_times = le_i32s(chunk),
+                b"fade" => self.track_fades = le_i32s(chunk),
+                b"plst" => self.playlist = chunk.to_vec(),
+                id if id[0].is_ascii_uppercase() && full => {
+                    return Err(anyhow!("unsupported NSFe chunk: {}", String::from_utf8_lossy(id)));
+                },
+                id => debug!("skipping NSFe chunk {}", String::from_utf8_lossy(id))
+            }
+        }
+        if !has_info {
+            return Err(anyhow!("NSFe file has no INFO chunk"));
+        }
+        Ok(())
+    }
+
+    // PAL only tunes play on a PAL console, everything else on NTSC
+    pub fn region(&self) -> Region {
+        if self.region_flags & 0x03 == 0x01 {Region::PAL} else {Region::NTSC}
+    }
+}
+
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn le_i32s(data: &[u8]) -> Vec<i32> {
+    data.chunks_exact(4).map(|bytes| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+}
+
+// the memory map of an NSF player: RAM, the APU, the bank registers and
+// 40 KiB at $6000-$FFFF filled from the tune's 4 KiB banks
+pub struct NsfBus {
+    ram: Vec<u8>,
+    pub(crate) apu: apu::Apu,
+    // $6000-$FFFF, RAM up to $7FFF and ROM above unless the tune uses FDS
+    memory: Vec<u8>,
+    // the program data, padded so bank 0 starts at a 4 KiB boundary
+    rom: Vec<u8>,
+    bankswitched: bool,
+    fds: bool
+}
+
+impl NsfBus {
+    pub fn new(nsf: &Nsf) -> Self {
+        let bankswitched = nsf.banks.is_some();
+        let mut rom = Vec::new();
+        if bankswitched {
+            rom.resize(nsf.load_addr as usize & 0x0FFF, 0);
+        }
+        rom.extend_from_slice(&nsf.data);
+        if bankswitched {
+            rom.resize(rom.len().div_ceil(BANK_SIZE).max(1) * BANK_SIZE, 0);
+        }
+        NsfBus {
+            ram: vec![0; 2048],
+            apu: apu::Apu::new(),
+            memory: vec![0; 0xA000],
+            rom,
+            bankswitched,
+            fds: nsf.expansion & EXPANSION_FDS != 0
+        }
+    }
+
+    // clears RAM and maps the banks a tune starts with
+    fn load(&mut self, nsf: &Nsf) {
+        self.ram.iter_mut().for_each(|byte| *byte = 0);
+        self.memory.iter_mut().for_each(|byte| *byte = 0);
+        match nsf.banks {
+            Some(banks) => {
+                for (slot, bank) in banks.iter().enumerate() {
+                    self.switch_bank(slot + 2, *bank);
+                }
+                // FDS tunes start with the banks of $E000 and $F000 in the
+                // RAM at $6000 and $7000
+                if self.fds {
+                    self.switch_bank(0, banks[6]);
+                    self.switch_bank(1, banks[7]);
+                }
+            },
+            None => {
+                let start = (nsf.load_addr as usize).saturating_sub(0x6000);
+                let end = (start + self.rom.len()).min(self.memory.len());
+                self.memory[start..end].copy_from_slice(&self.rom[..end - start]);
+            }
+        }
+    }
+
+    // copies 4 KiB bank `bank` to slot `slot` of $6000-$FFFF
+    fn switch_bank(&mut self, slot: usize, bank: u8) {
+        let banks = self.rom.len() / BANK_SIZE;
+        let start = (bank as usize % banks) * BANK_SIZE;
+        self.memory[slot * BANK_SIZE..(slot + 1) * BANK_SIZE]
+            .copy_from_slice(&self.rom[start..start + BANK_SIZE]);
+    }
+
+    fn writable(&self, addr: u16) -> bool {
+        addr < 0x8000 || (self.fds && addr < 0xE000)
+    }
+}
+
+impl Bus for NsfBus {
+    fn read(&mut self, addr: u16) -> Result<u8> {
+        Ok(match addr {
+            addr if addr < 0x2000 => self.ram[(addr & 0x07FF) as usize],
+            0x4015 => self.apu.read_status(),
+            0x4100..=0x4102 => IDLE_LOOP[(addr - IDLE_ADDR) as usize],
+            addr if addr < 0x6000 => 0,
+            addr => self.memory[addr as usize - 0x6000]
+        })
+    }
+
+    fn write(&mut self, addr: u16, data: u8) -> Result<()> {
+        match addr {
+            addr if addr < 0x2000 => self.ram[(addr & 0x07FF) as usize] = data,
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data)?,
+            0x5FF6..=0x5FF7 if self.bankswitched && self.fds => {
+                self.switch_bank(addr as usize - 0x5FF6, data);
+            },
+            0x5FF8..=0x5FFF if self.bankswitched => {
+                self.switch_bank(addr as usize - 0x5FF8 + 2, data);
+            },
+            addr if addr >= 0x6000 && self.writable(addr) => {
+                self.memory[addr as usize - 0x6000] = data;
+            },
+            addr => debug!("ignored NSF write, address: {:#06x}, data: {:#04x}", addr, data)
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        Some(match addr {
+            addr if addr < 0x2000 => self.ram[(addr & 0x07FF) as usize],
+            0x4015 => self.apu.peek_status(),
+            0x4100..=0x4102 => IDLE_LOOP[(addr - IDLE_ADDR) as usize],
+            addr if addr < 0x6000 => 0,
+            addr => self.memory[addr as usize - 0x6000]
+        })
+    }
+
+    fn tick(&mut self) {
+        self.apu.step();
+    }
+
+    fn irq_line(&self) -> bool {
+        self.apu.irq_line()
+    }
+
+    fn poll_dmc_dma(&mut self) -> Option<u16> {
+        self.apu.dmc_dma_request()
+    }
+
+    fn complete_dmc_dma(&mut self, data: u8) {
+        self.apu.complete_dmc_dma(data);
+    }
+}
+
+// plays the tracks of an NSF, calling PLAY at the file's rate and
+// rendering the APU output at the host's sample rate. Expansion audio is
+// not emulated, tunes using it play without those channels, see
+// unsupported_expansion.
+pub struct NsfPlayer {
+    nsf: Nsf,
+    cpu: CPU<NsfBus>,
+    // index into the playlist, or the song number without one
+    track: usize,
+    // CPU cycles between PLAY calls and left until the next one
+    play_period: f64,
+    play_countdown: f64
+}
+
+impl NsfPlayer {
+    pub fn load_from_file(path: &str) -> Result<Self> {
+        NsfPlayer::new(Nsf::load_from_file(path)?)
+    }
+
+    pub fn new(nsf: Nsf) -> Result<Self> {
+        if nsf.songs == 0 {
+            return Err(anyhow!("NSF has no songs"));
+        }
+        if nsf.expansion != 0 {
+            debug!("expansion audio {:#04x} is not emulated", nsf.expansion);
+        }
+        let mut cpu = CPU::new(NsfBus::new(&nsf));
+        let region = nsf.region();
+        cpu.bus_mut().apu.set_region(region);
+        let speed = if region == Region::PAL {nsf.pal_speed} else {nsf.ntsc_speed};
+        let start = if nsf.playlist.is_empty() {nsf.start_song as usize} else {0};
+        let mut player = NsfPlayer {
+            nsf,
+            cpu,
+            track: 0,
+            play_period: speed as f64 * region.cpu_clock_hz() / 1_000_000.0,
+            play_countdown: 0.0
+        };
+        player.set_track(start)?;
+        Ok(player)
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    // EXPANSION_* bits of the chips the tune uses but the output lacks,
+    // which is all of them. FDS tunes only get the FDS memory layout.
+    pub fn unsupported_expansion(&self) -> u8 {
+        self.nsf.expansion & (EXPANSION_VRC6 | EXPANSION_VRC7 | EXPANSION_FDS |
+            EXPANSION_MMC5 | EXPANSION_N163 | EXPANSION_5B)
+    }
+
+    // mixer settings, channel capture and the sample rate
+    pub fn apu_mut(&mut self) -> &mut apu::Apu {
+        &mut self.cpu.bus_mut().apu
+    }
+
+    pub fn track_count(&self) -> usize {
+        if self.nsf.playlist.is_empty() {self.nsf.songs as usize} else {self.nsf.playlist.len()}
+    }
+
+    pub fn track(&self) -> usize {
+        self.track
+    }
+
+    // the song number the current track plays, zero based
+    pub fn song(&self) -> u8 {
+        self.nsf.playlist.get(self.track).copied().unwrap_or(self.track as u8)
+    }
+
+    // resets the machine and runs INIT for `track`
+    pub fn set_track(&mut self, track: usize) -> Result<()> {
+        if track >= self.track_count() {
+            return Err(anyhow!("track {} out of range, the file has {}", track, self.track_count()));
+        }
+        self.track = track;
+        let song = self.song();
+        let pal = self.nsf.region() == Region::PAL;
+        let bus = self.cpu.bus_mut();
+        bus.load(&self.nsf);
+        // silence the channels the way the NSF spec asks for
+        for addr in 0x4000..=0x4013 {
+            bus.write(addr, 0)?;
+        }
+        bus.write(0x4015, 0x00)?;
+        bus.write(0x4015, 0x0F)?;
+        bus.write(0x4017, 0x40)?;
+        self.cpu.reset()?;
+        self.cpu.call(self.nsf.init_addr, song, pal as u8, IDLE_ADDR)?;
+        self.play_countdown = self.play_period;
+        Ok(())
+    }
+
+    // wraps around at the last track
+    pub fn next_track(&mut self) -> Result<()> {
+        self.set_track((self.track + 1) % self.track_count())
+    }
+
+    pub fn prev_track(&mut self) -> Result<()> {
+        let count = self.track_count();
+        self.set_track((self.track + count - 1) % count)
+    }
+
+    // fills `out` with the next samples of the track
+    pub fn render(&mut self, out: &mut [f32]) -> Result<()> {
+        let mut filled = self.cpu.bus_mut().apu.audio_mut().read_samples(out);
+        while filled < out.len() {
+            self.run_play_period()?;
+            filled += self.cpu.bus_mut().apu.audio_mut().read_samples(&mut out[filled..]);
+        }
+        Ok(())
+    }
+
+    pub fn render_i16(&mut self, out: &mut [i16]) -> Result<()> {
+        let mut filled = self.cpu.bus_mut().apu.audio_mut().read_samples_i16(out);
+        while filled < out.len() {
+            self.run_play_period()?;
+            filled += self.cpu.bus_mut().apu.audio_mut().read_samples_i16(&mut out[filled..]);
+        }
+        Ok(())
+    }
+
+    // runs the CPU up to the next PLAY call and makes its audio available.
+    // PLAY is skipped when INIT or the last PLAY have not returned yet.
+    fn run_play_period(&mut self) -> Result<()> {
+        while self.play_countdown > 0.0 {
+            self.cpu.step()?;
+            self.play_countdown -= 1.0;
+        }
+        self.play_countdown += self.play_period;
+        if self.cpu.pc() == IDLE_ADDR {
+            self.cpu.call(self.nsf.play_addr, 0, 0, IDLE_ADDR)?;
+        }
+        self.cpu.bus_mut().apu.end_audio_frame();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // INIT stores the song in $00 and starts pulse 1, PLAY counts its
+    // calls in $01
+    fn test_program() -> Vec<u8> {
+        let mut program = vec![
+            0x85, 0x00,             // STA $00
+            0xA9, 0x01, 0x8D, 0x15, 0x40, // LDA #$01, STA $4015
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF, STA $4000
+            0xA9, 0xFD, 0x8D, 0x02, 0x40, // LDA #$FD, STA $4002
+            0xA9, 0x08, 0x8D, 0x03, 0x40, // LDA #$08, STA $4003
+            0x60                    // RTS
+        ];
+        program.resize(0x20, 0xEA);
+        program.extend_from_slice(&[0xE6, 0x01, 0x60]); // INC $01, RTS
+        program
+    }
+
+    fn nsf_file(banks: [u8; 8], data: &[u8]) -> Vec<u8> {
+        let mut file = vec![0; 0x80];
+        file[..5].copy_from_slice(b"NESM\x1A");
+        file[0x05] = 1;
+        file[0x06] = 3;
+        file[0x07] = 2;
+        file[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x20, 0x80]);
+        file[0x0E..0x13].copy_from_slice(b"Title");
+        file[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        file[0x70..0x78].copy_from_slice(&banks);
+        file.extend_from_slice(data);
+        file
+    }
+
+    #[test]
+    fn parses_nsf_header() {
+        let nsf = Nsf::parse(&nsf_file([0; 8], &test_program())).unwrap();
+        assert_eq!(nsf.name, "Title");
+        assert_eq!((nsf.songs, nsf.start_song), (3, 1));
+        assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8000, 0x8020));
+        assert_eq!(nsf.banks, None);
+        assert_eq!(nsf.region(), Region::NTSC);
+        assert!(Nsf::parse(b"NESM").is_err());
+    }
+
+    #[test]
+    fn parses_nsfe_chunks() {
+        let mut file = b"NSFE".to_vec();
+        let mut chunk = |id: &[u8], data: &[u8]| {
+            file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(id);
+            file.extend_from_slice(data);
+        };
+        chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x20, 0x80, 0x01, 0x00, 0x02, 0x01]);
+        chunk(b"DATA", &test_program());
+        chunk(b"auth", b"Game\0Artist\0\0Ripper\0");
+        chunk(b"tlbl", b"Intro\0Theme\0");
+        chunk(b"time", &[0x10, 0x27, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        chunk(b"plst", &[1, 0]);
+        chunk(b"xtra", &[0; 3]);
+        chunk(b"NEND", &[]);
+        let nsf = Nsf::parse(&file).unwrap();
+        assert_eq!((nsf.name.as_str(), nsf.artist.as_str(), nsf.ripper.as_str()), ("Game", "Artist", "Ripper"));
+        assert_eq!(nsf.track_labels, vec!["Intro", "Theme"]);
+        assert_eq!(nsf.track_times, vec![10_000, -1]);
+        assert_eq!(nsf.region(), Region::PAL);
+        assert_eq!(nsf.pal_speed, DEFAULT_PAL_SPEED);
+
+        let player = NsfPlayer::new(nsf).unwrap();
+        assert_eq!(player.track_count(), 2);
+        assert_eq!(player.song(), 1);
+
+        let mut required = b"NSFE".to_vec();
+        required.extend_from_slice(&[0, 0, 0, 0]);
+        required.extend_from_slice(b"VRC7");
+        assert!(Nsf::parse(&required).is_err());
+    }
+
+    #[test]
+    fn calls_init_and_play() {
+        let mut player = NsfPlayer::new(Nsf::parse(&nsf_file([0; 8], &test_program())).unwrap()).unwrap();
+        player.apu_mut().set_sample_rate(48_000);
+        assert_eq!(player.track(), 1);
+        // a second of audio calls PLAY about 60 times
+        let mut out = vec![0.0; 48_000];
+        player.render(&mut out).unwrap();
+        assert_eq!(player.unsupported_expansion(), 0);
+        let ram = &player.cpu.bus().ram;
+        assert_eq!(ram[0x00], 1);
+        assert!((59..=61).contains(&ram[0x01]), "{} calls", ram[0x01]);
+        assert!(out.iter().any(|sample| sample.abs() > 0.05));
+
+        player.next_track().unwrap();
+        player.next_track().unwrap();
+        assert_eq!(player.track(), 0);
+        player.prev_track().unwrap();
+        assert_eq!(player.track(), 2);
+        let mut out = [0i16; 1000];
+        player.render_i16(&mut out).unwrap();
+        assert_eq!(player.cpu.bus().ram[0x00], 2);
+    }
+
+    #[test]
+    fn reports_expansion_audio() {
+        let mut file = nsf_file([0; 8], &test_program());
+        file[0x7B] = EXPANSION_VRC6 | EXPANSION_FDS;
+        let player = NsfPlayer::new(Nsf::parse(&file).unwrap()).unwrap();
+        assert_eq!(player.unsupported_expansion(), EXPANSION_VRC6 | EXPANSION_FDS);
+    }
+
+    #[test]
+    fn idle_loop_is_visible_to_peek() {
+        let mut bus = NsfBus::new(&Nsf::parse(&nsf_file([0; 8], &test_program())).unwrap());
+        for addr in 0x4100..=0x4102 {
+            assert_eq!(bus.peek(addr), Some(bus.read(addr).unwrap()));
+        }
+        assert_eq!(bus.peek(0x4100), Some(0x4C));
+    }
+
+    #[test]
+    fn switches_banks() {
+        // loaded at $8800, so the program after $0800 bytes of data starts
+        // bank 1 and bank 0 is padding and zeros
+        let mut data = vec![0; 0x0800];
+        let mut program = test_program();
+        program[0] = 0xEA;
+        program[1] = 0xEA;
+        data.extend_from_slice(&program);
+        let mut file = nsf_file([1, 0, 0, 0, 0, 0, 0, 0], &data);
+        file[0x08..0x0E].copy_from_slice(&[0x00, 0x88, 0x00, 0x80, 0x20, 0x80]);
+        let mut bus = NsfBus::new(&Nsf::parse(&file).unwrap());
+        bus.load(&Nsf::parse(&file).unwrap());
+        assert_eq!(bus.read(0x8000).unwrap(), 0xEA);
+        assert_eq!(bus.read(0x9000).unwrap(), 0x00);
+        bus.write(0x5FF9, 0x01).unwrap();
+        assert_eq!(bus.read(0x9002).unwrap(), 0xA9);
+        // ROM stays read only
+        bus.write(0x8000, 0x00).unwrap();
+        assert_eq!(bus.read(0x8000).unwrap(), 0xEA);
+    }
+}
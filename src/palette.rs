@@ -0,0 +1,264 @@
+// turns the 6-bit colour indices produced by the PPU into displayable RGB
+use std::f64::consts::PI;
+use std::fs;
+use anyhow::{anyhow, Result};
+
+// 2C02 master palette, one RGB triplet per colour index
+static NTSC_PALETTE: [u8; 192] = [
+    0x66, 0x66, 0x66, 0x00, 0x2A, 0x88, 0x14, 0x12, 0xA7, 0x3B, 0x00, 0xA4,
+    0x5C, 0x00, 0x7E, 0x6E, 0x00, 0x40, 0x6C, 0x06, 0x00, 0x56, 0x1D, 0x00,
+    0x33, 0x35, 0x00, 0x0B, 0x48, 0x00, 0x00, 0x52, 0x00, 0x00, 0x4F, 0x08,
+    0x00, 0x40, 0x4D, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xAD, 0xAD, 0xAD, 0x15, 0x5F, 0xD9, 0x42, 0x40, 0xFF, 0x75, 0x27, 0xFE,
+    0xA0, 0x1A, 0xCC, 0xB7, 0x1E, 0x7B, 0xB5, 0x31, 0x20, 0x99, 0x4E, 0x00,
+    0x6B, 0x6D, 0x00, 0x38, 0x87, 0x00, 0x0C, 0x93, 0x00, 0x00, 0x8F, 0x32,
+    0x00, 0x7C, 0x8D, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFE, 0xFF, 0x64, 0xB0, 0xFF, 0x92, 0x90, 0xFF, 0xC6, 0x76, 0xFF,
+    0xF3, 0x6A, 0xFF, 0xFE, 0x6E, 0xCC, 0xFE, 0x81, 0x70, 0xEA, 0x9E, 0x22,
+    0xBC, 0xBE, 0x00, 0x88, 0xD8, 0x00, 0x5C, 0xE4, 0x30, 0x45, 0xE0, 0x82,
+    0x48, 0xCD, 0xDE, 0x4F, 0x4F, 0x4F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFE, 0xFF, 0xC0, 0xDF, 0xFF, 0xD3, 0xD2, 0xFF, 0xE8, 0xC8, 0xFF,
+    0xFB, 0xC2, 0xFF, 0xFE, 0xC4, 0xEA, 0xFE, 0xCC, 0xC5, 0xF7, 0xD8, 0xA5,
+    0xE4, 0xE5, 0x94, 0xCF, 0xEF, 0x96, 0xBD, 0xF4, 0xAB, 0xB3, 0xF3, 0xCC,
+    0xB5, 0xEB, 0xF2, 0xB8, 0xB8, 0xB8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+];
+
+// how much an emphasis bit darkens the two channels it does not name
+static EMPHASIS_ATTENUATION: f64 = 0.816328;
+
+// composite voltages relative to sync, the low and high level of each
+// luma row, and the black and white points used for decoding
+static SIGNAL_LOW: [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+static SIGNAL_HIGH: [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+static SIGNAL_BLACK: f64 = 0.518;
+static SIGNAL_WHITE: f64 = 1.962;
+static SIGNAL_ATTENUATION: f64 = 0.746;
+// a square wave carries less chroma than a sine of the same swing
+static CHROMA_GAIN: f64 = 1.5;
+
+// knobs of the composite decoder in Palette::generate
+#[derive(Clone, Copy, Debug)]
+pub struct NtscParams {
+    // rotation of the colour wheel in degrees
+    pub hue: f64,
+    pub saturation: f64,
+    pub contrast: f64,
+    // added to luma, -1.0 to 1.0
+    pub brightness: f64,
+    // gamma of the display the palette is meant for, 2.2 leaves the
+    // decoded signal as it is
+    pub gamma: f64
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FrameFormat {
+    // one 6-bit palette index per pixel
+    Indexed,
+    // four bytes per pixel, alpha always opaque
+    RGBA
+}
+
+// 64 colours for each of the eight PPUMASK emphasis combinations
+pub struct Palette {
+    colors: Vec<[u8; 3]>
+}
+
+impl Palette {
+    pub fn ntsc() -> Self {
+        Palette::with_emphasis(&NTSC_PALETTE)
+    }
+
+    // reads a .pal file, see from_pal_bytes
+    pub fn load_from_file(path: &str) -> Result<Self> {
+        Palette::from_pal_bytes(&fs::read(path)?)
+    }
+
+    // 192 byte files hold the 64 base colours and get the emphasis
+    // variants derived, 1536 byte files carry all eight sets themselves
+    pub fn from_pal_bytes(data: &[u8]) -> Result<Self> {
+        match data.len() {
+            192 => Ok(Palette::with_emphasis(data)),
+            1536 => Ok(Palette {
+                colors: data.chunks(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect()
+            }),
+            len => Err(anyhow!("invalid palette size: {} bytes", len))
+        }
+    }
+
+    // the palette in .pal layout, 1536 bytes with the emphasis sets or 192 without
+    pub fn to_pal_bytes(&self, emphasis: bool) -> Vec<u8> {
+        let count = if emphasis {512} else {64};
+        self.colors.iter().take(count).flat_map(|rgb| rgb.iter().copied()).collect()
+    }
+
+    // decodes the 2C02 composite signal of every colour and emphasis
+    // combination, sampling the 12 phases of one colour subcarrier cycle
+    pub fn generate(params: &NtscParams) -> Self {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8u8 {
+            for index in 0..64u8 {
+                let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+                for phase in 0..12 {
+                    let level = (ntsc_signal(index, emphasis, phase) - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK);
+                    // shifted by four phases so I and Q line up with the burst
+                    let angle = PI * (phase + 4) as f64 / 6.0 + params.hue.to_radians();
+                    y += level;
+                    i += level * angle.cos();
+                    q += level * angle.sin();
+                }
+                y = y / 12.0 * params.contrast + params.brightness;
+                i = i / 12.0 * CHROMA_GAIN * params.saturation * params.contrast;
+                q = q / 12.0 * CHROMA_GAIN * params.saturation * params.contrast;
+                let rgb = [
+                    y + 0.946882 * i + 0.623557 * q,
+                    y - 0.274788 * i - 0.635691 * q,
+                    y - 1.108545 * i + 1.709007 * q
+                ];
+                colors.push(rgb.map(|c| gamma_correct(c, params.gamma)));
+            }
+        }
+        Palette {
+            colors
+        }
+    }
+
+    // `base` holds 64 RGB triplets, the emphasis variants are derived
+    fn with_emphasis(base: &[u8]) -> Self {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8u8 {
+            for rgb in base.chunks(3).take(64) {
+                let mut color = [rgb[0], rgb[1], rgb[2]];
+                // bit 0 emphasises red, bit 1 green and bit 2 blue
+                for (channel, value) in color.iter_mut().enumerate() {
+                    if emphasis & !(1 << channel) != 0 {
+                        *value = (*value as f64 * EMPHASIS_ATTENUATION).round() as u8;
+                    }
+                }
+                colors.push(color);
+            }
+        }
+        Palette {
+            colors
+        }
+    }
+
+    // `emphasis` is PPUMASK bits 5-7 shifted down
+    pub fn rgb(&self, index: u8, emphasis: u8) -> [u8; 3] {
+        self.colors[((emphasis as usize & 0x07) << 6) | (index as usize & 0x3F)]
+    }
+
+    // converts a frame of indices into RGBA8888, replacing `out`
+    pub fn write_rgba(&self, indices: &[u8], emphasis: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        out.reserve(indices.len() * 4);
+        for (index, emphasis) in indices.iter().zip(emphasis) {
+            out.extend_from_slice(&self.rgb(*index, *emphasis));
+            out.push(0xFF);
+        }
+    }
+}
+
+// the voltage of `index` at one of the 12 subcarrier phases
+fn ntsc_signal(index: u8, emphasis: u8, phase: u8) -> f64 {
+    let color = index & 0x0F;
+    // colours $xE and $xF are black whatever the luma bits say
+    let level = if color > 13 {1} else {(index >> 4) & 0x03} as usize;
+    let mut low = SIGNAL_LOW[level];
+    let mut high = SIGNAL_HIGH[level];
+    if color == 0 {
+        low = high;
+    }
+    if color > 12 {
+        high = low;
+    }
+    let in_phase = |color: u8| (color + phase) % 12 < 6;
+    let mut signal = if in_phase(color) {high} else {low};
+    // each emphasis bit attenuates the signal during a third of the cycle
+    if (emphasis & 0x01 != 0 && in_phase(0)) ||
+        (emphasis & 0x02 != 0 && in_phase(4)) ||
+        (emphasis & 0x04 != 0 && in_phase(8)) {
+        signal *= SIGNAL_ATTENUATION;
+    }
+    signal
+}
+
+fn gamma_correct(value: f64, gamma: f64) -> u8 {
+    let value = value.clamp(0.0, 1.0).powf(2.2 / gamma);
+    (value * 255.0).round() as u8
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::ntsc()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_indices_to_rgba() {
+        let palette = Palette::ntsc();
+        let mut out = Vec::new();
+        palette.write_rgba(&[0x00, 0x0F, 0x20], &[0, 0, 0], &mut out);
+        assert_eq!(out, vec![
+            0x66, 0x66, 0x66, 0xFF,
+            0x00, 0x00, 0x00, 0xFF,
+            0xFF, 0xFE, 0xFF, 0xFF
+        ]);
+    }
+
+    #[test]
+    fn pal_files_round_trip() {
+        let palette = Palette::ntsc();
+        let base = palette.to_pal_bytes(false);
+        assert_eq!(base.len(), 192);
+        assert_eq!(Palette::from_pal_bytes(&base).unwrap().to_pal_bytes(true), palette.to_pal_bytes(true));
+
+        let mut full = palette.to_pal_bytes(true);
+        assert_eq!(full.len(), 1536);
+        full[64 * 3] = 0x12;
+        assert_eq!(Palette::from_pal_bytes(&full).unwrap().rgb(0x00, 0x01), [0x12, 0x53, 0x53]);
+        assert!(Palette::from_pal_bytes(&full[..100]).is_err());
+    }
+
+    #[test]
+    fn generated_palette_matches_builtin() {
+        let palette = Palette::generate(&NtscParams::default());
+        assert_eq!(palette.rgb(0x0F, 0), [0, 0, 0]);
+        assert_eq!(palette.rgb(0x30, 0), [255, 255, 255]);
+        // the default parameters reproduce the built-in palette
+        let builtin = Palette::ntsc();
+        for index in 0..64 {
+            let diff = palette.rgb(index, 0).iter().zip(builtin.rgb(index, 0).iter())
+                .map(|(a, b)| (*a as i16 - *b as i16).abs())
+                .max();
+            assert!(diff <= Some(6), "colour {:#04x}", index);
+        }
+        // blue emphasis darkens a grey
+        let [r, _, b] = palette.rgb(0x10, 0x04);
+        assert!(r < palette.rgb(0x10, 0)[0] && b > r);
+    }
+
+    #[test]
+    fn emphasis_darkens_other_channels() {
+        let palette = Palette::ntsc();
+        assert_eq!(palette.rgb(0x20, 0x01), [0xFF, 0xCF, 0xD0]);
+        assert_eq!(palette.rgb(0x20, 0x07), [0xD0, 0xCF, 0xD0]);
+        assert_eq!(palette.rgb(0x0F, 0x07), [0x00, 0x00, 0x00]);
+    }
+}
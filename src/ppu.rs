@@ -1,14 +1,20 @@
 use anyhow::{anyhow, Result};
-use std::cell::RefCell;
-use std::rc::Rc;
 use crate::utils;
 use crate::cart;
+use crate::clock::Region;
 
+static STATUS_VBLANK: u8 = 0x80;
+static STATUS_SPRITE0: u8 = 0x40;
+static STATUS_OVERFLOW: u8 = 0x20;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct PPU {
     vram: Vec<u8>,
     oam: Vec<u8>,
     palette: Vec<u8>,
-    frame: Rc<RefCell<Vec<u8>>>,
+    frame: Vec<u8>,
+    // PPUMASK emphasis bits in effect for each pixel of `frame`
+    emphasis: Vec<u8>,
     cycles: u16,
     scanline: u16,
     // registers
@@ -16,29 +22,54 @@ pub struct PPU {
     r_ppumask: u8,
     r_ppustatus: u8,
     r_oamaddr: u8,
-    r_oamdata: u8,
-    r_ppuaddr: u8,
-    r_ppudata: u8,
-    r_oamdma: u8,
-    x_scroll: u16,
-    y_scroll: u16,
-    scroll_first_write: bool,
+    // internal scroll registers: current and temporary vram address,
+    // fine x scroll and the shared $2005/$2006 write toggle
+    r_v: u16,
+    r_t: u16,
+    r_x: u8,
+    r_w: bool,
+    // PPUDATA reads return the previous read
+    read_buffer: u8,
+    // last value written to any register, returned by write-only ones
+    io_latch: u8,
+    // background fetch latches and shift registers
+    bg_next_tile: u8,
+    bg_next_attr: u8,
+    bg_next_low: u8,
+    bg_next_high: u8,
+    bg_shift_low: u16,
+    bg_shift_high: u16,
+    bg_shift_attr_low: u16,
+    bg_shift_attr_high: u16,
+    // up to eight sprites fetched for the line being drawn
+    sprite_count: usize,
+    sprite_x: [u8; 8],
+    sprite_attr: [u8; 8],
+    sprite_low: [u8; 8],
+    sprite_high: [u8; 8],
+    sprite_zero_on_line: bool,
 
     even_frame: bool,
     stage: Stage,
-    
-    cart: Rc<RefCell<cart::Cartridge>>,
-    vblank_cb: Option<Box<dyn Fn()>>
+    region: Region,
+    // set once per frame when vblank starts, see poll_frame_complete
+    frame_complete: bool,
+    // a $2002 read just before vblank keeps the flag from being set
+    suppress_vblank: bool,
+
+    nmi_occurred: bool
 }
 
 impl PPU {
 
-    pub fn new(frame: Rc<RefCell<Vec<u8>>>, cart: Rc<RefCell<cart::Cartridge>>) -> Self {
+    pub fn new() -> Self {
         PPU {
-            vram: Vec::with_capacity(0xFFF as usize),
-            oam: Vec::with_capacity(512),
-            palette: Vec::with_capacity(0x20 as usize),
-            frame: frame,
+            // two nametables on the console, four-screen carts add two more
+            vram: vec![0; 0x1000],
+            oam: vec![0; 256],
+            palette: vec![0; 0x20],
+            frame: vec![0; 256 * 240],
+            emphasis: vec![0; 256 * 240],
             cycles: 0,
             scanline: 0,
             // registers
@@ -46,25 +77,150 @@ impl PPU {
             r_ppumask: 0,
             r_ppustatus: 0,
             r_oamaddr: 0,
-            r_oamdata: 0,
-            r_ppuaddr: 0,
-            r_ppudata: 0,
-            r_oamdma: 0,
-            x_scroll: 0,
-            y_scroll: 0,
-            scroll_first_write: true,
+            r_v: 0,
+            r_t: 0,
+            r_x: 0,
+            r_w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            bg_next_tile: 0,
+            bg_next_attr: 0,
+            bg_next_low: 0,
+            bg_next_high: 0,
+            bg_shift_low: 0,
+            bg_shift_high: 0,
+            bg_shift_attr_low: 0,
+            bg_shift_attr_high: 0,
+            sprite_count: 0,
+            sprite_x: [0; 8],
+            sprite_attr: [0; 8],
+            sprite_low: [0; 8],
+            sprite_high: [0; 8],
+            sprite_zero_on_line: false,
             even_frame: true,
-            stage: Stage::PreRendering,
-            cart: cart,
-            vblank_cb: None
+            stage: Stage::Rendering,
+            region: Region::NTSC,
+            frame_complete: false,
+            suppress_vblank: false,
+            nmi_occurred: false
+        }
+    }
+
+    // CPU writes to $2000-$2007, `addr` is already mirrored down
+    pub fn write_register(&mut self, cart: &mut cart::Cartridge, addr: u16, data: u8) -> Result<()> {
+        self.io_latch = data;
+        match addr {
+            0x2000 => self.write_ppuctrl(data),
+            0x2001 => self.r_ppumask = data,
+            0x2002 => {},
+            0x2003 => self.r_oamaddr = data,
+            0x2004 => self.write_oamdata(data),
+            0x2005 => self.write_scroll(data),
+            0x2006 => self.write_ppuaddr(data),
+            0x2007 => return self.write_ppudata(cart, data),
+            addr => return Err(anyhow!("unknown ppu register: {:#06x}", addr))
         }
+        Ok(())
+    }
+
+    // CPU reads from $2000-$2007, `addr` is already mirrored down
+    pub fn read_register(&mut self, cart: &cart::Cartridge, addr: u16) -> Result<u8> {
+        let data = match addr {
+            0x2002 => self.read_ppustatus(),
+            0x2004 => self.oam[self.r_oamaddr as usize],
+            0x2007 => self.read_ppudata(cart)?,
+            addr if addr <= 0x2007 => self.io_latch,
+            addr => return Err(anyhow!("unknown ppu register: {:#06x}", addr))
+        };
+        self.io_latch = data;
+        Ok(data)
     }
-    fn set_scroll(&mut self, data: u8) {
-        if self.scroll_first_write {
-            self.x_scroll = if self.r_ppuctrl & 0x1 == 1 {256 + data as u16} else {data as u16};
-        }else {
-            self.y_scroll = if self.r_ppuctrl & 0x2 == 1 {240+ data as u16} else {data as u16};
+
+    fn write_ppuctrl(&mut self, data: u8) {
+        let nmi_enabled = self.r_ppuctrl & 0x80 != 0;
+        if data & 0x80 == 0 {
+            // an edge not yet seen by the CPU goes away with the enable bit
+            self.nmi_occurred = false;
+        } else if !nmi_enabled && self.r_ppustatus & STATUS_VBLANK != 0 {
+            // enabling NMI during vblank raises one straight away
+            self.nmi_occurred = true;
         }
+        self.r_ppuctrl = data;
+        // nametable select goes to bits 10-11 of t
+        self.r_t = (self.r_t & 0xF3FF) | ((data as u16 & 0x03) << 10);
+    }
+
+    fn write_oamdata(&mut self, data: u8) {
+        self.oam[self.r_oamaddr as usize] = data;
+        self.r_oamaddr = self.r_oamaddr.wrapping_add(1);
+    }
+
+    fn write_scroll(&mut self, data: u8) {
+        if !self.r_w {
+            // coarse x and fine x
+            self.r_t = (self.r_t & 0xFFE0) | (data as u16 >> 3);
+            self.r_x = data & 0x07;
+        } else {
+            // coarse y and fine y
+            self.r_t = (self.r_t & 0x8C1F) | ((data as u16 & 0x07) << 12) | ((data as u16 & 0xF8) << 2);
+        }
+        self.r_w = !self.r_w;
+    }
+
+    fn write_ppuaddr(&mut self, data: u8) {
+        if !self.r_w {
+            // the high byte write also clears bit 14
+            self.r_t = (self.r_t & 0x00FF) | ((data as u16 & 0x3F) << 8);
+        } else {
+            self.r_t = (self.r_t & 0xFF00) | data as u16;
+            self.r_v = self.r_t;
+        }
+        self.r_w = !self.r_w;
+    }
+
+    fn read_ppustatus(&mut self) -> u8 {
+        // the low bits are whatever was last on the PPU data bus
+        let data = (self.r_ppustatus & 0xE0) | (self.io_latch & 0x1F);
+        if self.scanline == self.vblank_line() {
+            match self.cycles {
+                // one dot before the flag goes up: it stays down all frame
+                1 => self.suppress_vblank = true,
+                // on the same or the following dot: the flag reads set but
+                // the NMI is cancelled
+                2 | 3 => self.nmi_occurred = false,
+                _ => {}
+            }
+        }
+        self.r_ppustatus &= !STATUS_VBLANK;
+        self.r_w = false;
+        data
+    }
+
+    fn write_ppudata(&mut self, cart: &mut cart::Cartridge, data: u8) -> Result<()> {
+        self.write(cart, self.r_v & 0x3FFF, data)?;
+        self.increment_vram_addr();
+        Ok(())
+    }
+
+    fn read_ppudata(&mut self, cart: &cart::Cartridge) -> Result<u8> {
+        let addr = self.r_v & 0x3FFF;
+        let data = if addr >= 0x3F00 {
+            // palette reads skip the buffer, which picks up the
+            // nametable byte underneath instead
+            self.read_buffer = self.read(cart, addr - 0x1000)?;
+            self.read_palette(addr)? & self.greyscale_mask()
+        } else {
+            let buffered = self.read_buffer;
+            self.read_buffer = self.read(cart, addr)?;
+            buffered
+        };
+        self.increment_vram_addr();
+        Ok(data)
+    }
+
+    fn increment_vram_addr(&mut self) {
+        let step = if self.r_ppuctrl & 0x04 != 0 {32} else {1};
+        self.r_v = self.r_v.wrapping_add(step) & 0x7FFF;
     }
 
     pub fn reset(&mut self) -> Result<()> {
@@ -72,104 +228,813 @@ impl PPU {
         Ok(())
     }
 
-    pub fn set_vblank_cb(&mut self, cb: Box<dyn Fn()>) {
-        self.vblank_cb = Some(cb);
+    // PAL and Dendy frames have 312 lines and no skipped dot
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    fn vblank_line(&self) -> u16 {
+        match self.region {
+            Region::NTSC | Region::PAL => 241,
+            Region::Dendy => 291
+        }
+    }
+
+    fn prerender_line(&self) -> u16 {
+        match self.region {
+            Region::NTSC => 261,
+            Region::PAL | Region::Dendy => 311
+        }
+    }
+
+    // (scanline, dot) of the next PPU cycle
+    pub fn position(&self) -> (u16, u16) {
+        (self.scanline, self.cycles)
+    }
+
+    // dots left to run before the one raising the vblank flag and NMI.
+    // Never more than the real count, the next frame may be a dot short.
+    pub fn dots_before_vblank(&self) -> u64 {
+        let now = self.scanline as u64 * 341 + self.cycles as u64;
+        let vblank = self.vblank_line() as u64 * 341 + 1;
+        if now <= vblank {
+            vblank - now
+        } else {
+            (self.prerender_line() as u64 + 1) * 341 - now + vblank - 1
+        }
+    }
+
+    // 6-bit palette indices, one byte per pixel
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    pub fn frame_emphasis(&self) -> &[u8] {
+        &self.emphasis
+    }
+
+    // the NMI output towards the CPU, cleared once the bus has seen it
+    pub fn poll_nmi(&mut self) -> bool {
+        let occurred = self.nmi_occurred;
+        self.nmi_occurred = false;
+        occurred
     }
 
-    pub fn step(&mut self) -> Result<()> {
+    // true once for every frame finished since the last poll
+    pub fn poll_frame_complete(&mut self) -> bool {
+        let complete = self.frame_complete;
+        self.frame_complete = false;
+        complete
+    }
+
+    pub fn step(&mut self, cart: &mut cart::Cartridge) -> Result<()> {
         match self.stage {
-            Stage::PreRendering =>  self.pre_rendering(),
-            Stage::Rendering => self.rendering(),
-            Stage::PostRendering => self.post_rendering()
+            Stage::PreRendering =>  self.pre_rendering(cart)?,
+            Stage::Rendering => self.rendering(cart)?,
+            Stage::PostRendering => self.post_rendering()?
         }
+        self.next_dot();
+        Ok(())
     }
 
-    pub fn pre_rendering(&mut self) -> Result<()> {
-        if self.end_prerendering() {
+    fn next_dot(&mut self) {
+        if self.stage == Stage::PreRendering && self.end_prerendering() {
             self.cycles = 0;
             self.scanline = 0;
+            self.even_frame = !self.even_frame;
             self.stage = Stage::Rendering;
+            return;
         }
         self.cycles += 1;
+        if self.cycles > 340 {
+            self.cycles = 0;
+            self.scanline += 1;
+            self.stage = if self.scanline < 240 {
+                Stage::Rendering
+            } else if self.scanline == self.prerender_line() {
+                Stage::PreRendering
+            } else {
+                Stage::PostRendering
+            };
+        }
+    }
+
+    pub fn pre_rendering(&mut self, cart: &cart::Cartridge) -> Result<()> {
+        if self.cycles == 1 {
+            self.r_ppustatus &= !(STATUS_VBLANK | STATUS_SPRITE0 | STATUS_OVERFLOW);
+        }
+        if !self.rendering_enabled() {
+            return Ok(());
+        }
+        self.fetch_background(cart)?;
+        if self.cycles == 257 {
+            // nothing is evaluated for the first visible line
+            self.r_oamaddr = 0;
+            self.sprite_count = 0;
+            self.sprite_zero_on_line = false;
+        }
+        if (280..=304).contains(&self.cycles) {
+            self.copy_vertical();
+        }
         Ok(())
     }
     fn end_prerendering(&self) -> bool {
         if self.cycles == 340 {
             return true;
         }
-        if self.cycles == 339 && !self.even_frame && self.show_background() && self.show_sprites() {
+        // odd NTSC frames drop the last dot of the pre-render line
+        if self.cycles == 339 && !self.even_frame && self.region == Region::NTSC && self.rendering_enabled() {
             return true;
         } 
         false
     }
-    pub fn rendering(&mut self) -> Result<()> {
+    pub fn rendering(&mut self, cart: &cart::Cartridge) -> Result<()> {
         if self.cycles == 0 {
             // idle cycle
             return Ok(())
         }
-        if self.cycles > 0 && self.cycles <= 256 {
-            // background pixel
-            let x = self.cycles - 1 + self.x_scroll;
-            let y = self.scanline + self.y_scroll;
-            let tile_idx: u16 = ((y >> 3) << 5) + (x >> 3);
-            let tile_row_addr_low =
-                (((self.r_ppuctrl & 0x10) as u16) << 4) |
-                ((y >> 3) << 8) |
-                ((x >> 3) << 4)  |
-                (y & 0x0007);
-            let tile_row_addr_upper =
-                (((self.r_ppuctrl & 0x10) as u16) << 4) |
-                ((y >> 3) << 8) |
-                ((x >> 3) << 4)  |
-                0x0008 |
-                (y & 0x0007);
-            let addribute_table_addr =
-                
-                (((self.y_scroll >> 5) << 3) as u16) |
-                (((self.x_scroll >> 5) << 3) as u16)
-            let tile_row_low_byte = self.read(tile_row_addr_low)?;
-            let tile_row_upper_byte = self.read(tile_row_addr_upper)?;
-            let palette_addr = 0x3F00 | 0x0010 |  
-            
+        if self.rendering_enabled() {
+            self.fetch_background(cart)?;
+            if self.cycles == 257 {
+                self.r_oamaddr = 0;
+                self.evaluate_sprites(cart)?;
+            }
+        }
+        if self.cycles <= 256 {
+            self.render_pixel()?;
         }
-        self.cycles += 1;
         Ok(())
     }
     pub fn post_rendering(&mut self) -> Result<()> {
+        if self.scanline != self.vblank_line() || self.cycles != 1 {
+            return Ok(());
+        }
+        if !self.suppress_vblank {
+            self.r_ppustatus |= STATUS_VBLANK;
+            if self.r_ppuctrl & 0x80 != 0 {
+                self.nmi_occurred = true;
+            }
+        }
+        self.suppress_vblank = false;
+        self.frame_complete = true;
         Ok(())
     }
-    
+
+    // tile fetches for the visible dots and the two tiles prefetched
+    // for the next line, one memory access every other dot
+    fn fetch_background(&mut self, cart: &cart::Cartridge) -> Result<()> {
+        let dot = self.cycles;
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_background();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.bg_next_tile = self.read(cart, 0x2000 | (self.r_v & 0x0FFF))?;
+                },
+                2 => {
+                    let v = self.r_v;
+                    let attr = self.read(cart, 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07))?;
+                    // each attribute byte covers four 16x16 quadrants
+                    let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                    self.bg_next_attr = (attr >> shift) & 0x03;
+                },
+                4 => self.bg_next_low = self.read(cart, self.background_pattern_addr())?,
+                6 => self.bg_next_high = self.read(cart, self.background_pattern_addr() + 8)?,
+                7 => self.increment_coarse_x(),
+                _ => {}
+            }
+        }
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            self.copy_horizontal();
+        }
+        Ok(())
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        let table = if self.r_ppuctrl & 0x10 != 0 {0x1000} else {0};
+        let fine_y = (self.r_v >> 12) & 0x07;
+        table | (self.bg_next_tile as u16) << 4 | fine_y
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.bg_shift_low = (self.bg_shift_low & 0xFF00) | self.bg_next_low as u16;
+        self.bg_shift_high = (self.bg_shift_high & 0xFF00) | self.bg_next_high as u16;
+        // the attribute bits are stretched over the whole tile
+        let attr_low = if self.bg_next_attr & 0x01 != 0 {0xFF} else {0};
+        let attr_high = if self.bg_next_attr & 0x02 != 0 {0xFF} else {0};
+        self.bg_shift_attr_low = (self.bg_shift_attr_low & 0xFF00) | attr_low;
+        self.bg_shift_attr_high = (self.bg_shift_attr_high & 0xFF00) | attr_high;
+    }
+
+    fn shift_background(&mut self) {
+        self.bg_shift_low <<= 1;
+        self.bg_shift_high <<= 1;
+        self.bg_shift_attr_low <<= 1;
+        self.bg_shift_attr_high <<= 1;
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.r_v & 0x001F == 31 {
+            // wrap into the horizontally adjacent nametable
+            self.r_v &= !0x001F;
+            self.r_v ^= 0x0400;
+        } else {
+            self.r_v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.r_v & 0x7000 != 0x7000 {
+            self.r_v += 0x1000;
+            return;
+        }
+        self.r_v &= !0x7000;
+        let mut coarse_y = (self.r_v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.r_v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // rows 30 and 31 are attribute data, wrapping without a switch
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.r_v = (self.r_v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn copy_horizontal(&mut self) {
+        self.r_v = (self.r_v & !0x041F) | (self.r_t & 0x041F);
+    }
+
+    fn copy_vertical(&mut self) {
+        self.r_v = (self.r_v & !0x7BE0) | (self.r_t & 0x7BE0);
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.r_ppuctrl & 0x20 != 0 {16} else {8}
+    }
+
+    // picks the sprites for the next line out of OAM and fetches their
+    // pattern rows, standing in for the work of dots 65-320
+    fn evaluate_sprites(&mut self, cart: &cart::Cartridge) -> Result<()> {
+        let height = self.sprite_height();
+        let line = self.scanline;
+        let in_range = |y: u8| line >= y as u16 && line - (y as u16) < height;
+        self.sprite_count = 0;
+        self.sprite_zero_on_line = false;
+        let mut n = 0;
+        let mut found = [0usize; 8];
+        while n < 64 && self.sprite_count < 8 {
+            if in_range(self.oam[n * 4]) {
+                found[self.sprite_count] = n;
+                self.sprite_count += 1;
+                if n == 0 {
+                    self.sprite_zero_on_line = true;
+                }
+            }
+            n += 1;
+        }
+        // once eight sprites are found the hardware keeps looking for an
+        // overflow, but steps the byte offset along with the sprite index
+        // and ends up comparing tile, attribute and x bytes as y
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.r_ppustatus |= STATUS_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+        for (slot, index) in found.iter().take(self.sprite_count).enumerate() {
+            let sprite = &self.oam[index * 4..index * 4 + 4];
+            let (y, tile, attr, x) = (sprite[0], sprite[1], sprite[2], sprite[3]);
+            let mut row = line - y as u16;
+            if attr & 0x80 != 0 {
+                row = height - 1 - row;
+            }
+            let addr = if height == 16 {
+                // bit 0 of the tile selects the pattern table
+                let table = (tile as u16 & 0x01) << 12;
+                let top = tile as u16 & 0xFE;
+                let tile = if row >= 8 {top + 1} else {top};
+                table | tile << 4 | (row & 0x07)
+            } else {
+                let table = if self.r_ppuctrl & 0x08 != 0 {0x1000} else {0};
+                table | (tile as u16) << 4 | row
+            };
+            let mut low = self.read(cart, addr)?;
+            let mut high = self.read(cart, addr + 8)?;
+            if attr & 0x40 != 0 {
+                low = low.reverse_bits();
+                high = high.reverse_bits();
+            }
+            self.sprite_x[slot] = x;
+            self.sprite_attr[slot] = attr;
+            self.sprite_low[slot] = low;
+            self.sprite_high[slot] = high;
+        }
+        Ok(())
+    }
+
+    // (slot, pixel) of the first opaque sprite pixel at `x`
+    fn sprite_pixel(&self, x: u16) -> Option<(usize, u8)> {
+        for slot in 0..self.sprite_count {
+            let offset = x.wrapping_sub(self.sprite_x[slot] as u16);
+            if offset >= 8 {
+                continue;
+            }
+            let bit = 7 - offset;
+            let pixel = ((self.sprite_high[slot] >> bit) & 0x01) << 1 | ((self.sprite_low[slot] >> bit) & 0x01);
+            if pixel != 0 {
+                return Some((slot, pixel));
+            }
+        }
+        None
+    }
+
+    fn render_pixel(&mut self) -> Result<()> {
+        let x = self.cycles - 1;
+        let mut pixel = 0;
+        let mut palette = 0;
+        if self.show_background() && (x >= 8 || self.r_ppumask & 0x02 != 0) {
+            let bit = 0x8000 >> self.r_x;
+            pixel = ((self.bg_shift_high & bit != 0) as u8) << 1 | (self.bg_shift_low & bit != 0) as u8;
+            palette = ((self.bg_shift_attr_high & bit != 0) as u8) << 1 | (self.bg_shift_attr_low & bit != 0) as u8;
+        }
+        if self.show_sprites() && (x >= 8 || self.r_ppumask & 0x04 != 0) {
+            if let Some((slot, sprite)) = self.sprite_pixel(x) {
+                let attr = self.sprite_attr[slot];
+                if slot == 0 && self.sprite_zero_on_line && pixel != 0 && x != 255 {
+                    self.r_ppustatus |= STATUS_SPRITE0;
+                }
+                // sprites behind the background only show through its
+                // transparent pixels
+                if pixel == 0 || attr & 0x20 == 0 {
+                    pixel = sprite;
+                    palette = 0x04 | (attr & 0x03);
+                }
+            }
+        }
+        // transparent pixels show the backdrop colour at $3F00
+        let entry = if pixel == 0 {0} else {palette << 2 | pixel};
+        let color = self.read_palette(0x3F00 | entry as u16)? & self.greyscale_mask();
+        let pos = self.scanline as usize * 256 + x as usize;
+        self.frame[pos] = color;
+        self.emphasis[pos] = self.r_ppumask >> 5;
+        Ok(())
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.show_background() || self.show_sprites()
+    }
+
     fn show_background(&self) -> bool {
-        return utils::binaryBoolAnd(self.r_ppumask, 0x10);
+        utils::binaryBoolAnd(self.r_ppumask, 0x08)
     }
     fn show_sprites(&self) -> bool {
-        return utils::binaryBoolAnd(self.r_ppumask, 0x08);
+        utils::binaryBoolAnd(self.r_ppumask, 0x10)
     }
 
-    fn read(&self, addr: u16) -> Result<u8> {
+    fn read(&self, cart: &cart::Cartridge, addr: u16) -> Result<u8> {
         match addr {
-            addr if addr < 0x2000 => self.read_chr(addr),
-            addr if addr < 0x3f00 => self.read_nametable(addr),
+            addr if addr < 0x2000 => self.read_chr(cart, addr),
+            addr if addr < 0x3f00 => self.read_nametable(cart, addr),
             addr if addr < 0x4000 => self.read_palette(addr),
-            addr => anyhow!("unknown ppu address: {}", addr)
+            addr => Err(anyhow!("unknown ppu address: {}", addr))
         }
     }
     
-    fn read_chr(&self, addr: u16) -> Result<u8> {
-        self.cart.borrow().read_chr(addr)
+    fn write(&mut self, cart: &mut cart::Cartridge, addr: u16, data: u8) -> Result<()> {
+        match addr {
+            addr if addr < 0x2000 => cart.write_chr(addr, data),
+            addr if addr < 0x3f00 => {
+                self.vram[nametable_index(cart.get_mirror_type(), addr)] = data;
+                Ok(())
+            },
+            addr if addr < 0x4000 => {
+                self.palette[palette_index(addr)] = data & 0x3F;
+                Ok(())
+            },
+            addr => Err(anyhow!("unknown ppu address: {}", addr))
+        }
+    }
+
+    fn read_chr(&self, cart: &cart::Cartridge, addr: u16) -> Result<u8> {
+        cart.read_chr(addr)
     }
     
-    fn read_nametable(&self, addr: u16) -> Result<u8> {
-        Ok(self.vram[(addr & 0x7FF) as usize])
+    fn read_nametable(&self, cart: &cart::Cartridge, addr: u16) -> Result<u8> {
+        Ok(self.vram[nametable_index(cart.get_mirror_type(), addr)])
     }
     
     fn read_palette(&self, addr: u16) -> Result<u8> {
-        Ok(self.palette[(addr & 0x1F) as usize])
+        Ok(self.palette[palette_index(addr)])
+    }
+
+    // greyscale keeps only the brightness column of the palette
+    fn greyscale_mask(&self) -> u8 {
+        if self.r_ppumask & 0x01 != 0 {0x30} else {0x3F}
+    }
+}
+
+// VRAM offset of a $2000-$3EFF address under the given mirroring
+fn nametable_index(mirror_type: cart::MirrorType, addr: u16) -> usize {
+    let table = (addr as usize >> 10) & 0x03;
+    let bank = match mirror_type {
+        cart::MirrorType::HORIZONTAL => table >> 1,
+        cart::MirrorType::VERTICAL => table & 0x01,
+        cart::MirrorType::SINGLEA => 0,
+        cart::MirrorType::SINGLEB => 1,
+        cart::MirrorType::FOURSCREEN => table
+    };
+    bank << 10 | (addr as usize & 0x3FF)
+}
+
+// $3F10/$3F14/$3F18/$3F1C are shared with the background entries below them
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1F;
+    if index & 0x13 == 0x10 {index & 0x0F} else {index}
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+#[derive(PartialEq)]
 enum Stage {
     PreRendering,
     Rendering,
     PostRendering
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOTS_PER_FRAME: usize = 341 * 262;
+
+    // NROM with CHR RAM so tests can upload their own tiles
+    fn chr_ram_cart() -> cart::Cartridge {
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.resize(16 + 0x4000, 0);
+        let mut cart = cart::Cartridge::new();
+        cart.load_from_bytes(&rom).unwrap();
+        cart
+    }
+
+    fn write_vram(ppu: &mut PPU, cart: &mut cart::Cartridge, addr: u16, data: &[u8]) {
+        ppu.write_register(cart, 0x2006, (addr >> 8) as u8).unwrap();
+        ppu.write_register(cart, 0x2006, addr as u8).unwrap();
+        for byte in data {
+            ppu.write_register(cart, 0x2007, *byte).unwrap();
+        }
+    }
+
+    fn run_dots(ppu: &mut PPU, cart: &mut cart::Cartridge, dots: usize) {
+        for _ in 0..dots {
+            ppu.step(cart).unwrap();
+        }
+    }
+
+    // tile 1 is solid colour 1, tile 2 solid colour 3
+    fn background_scene() -> (PPU, cart::Cartridge) {
+        let mut ppu = PPU::new();
+        let mut cart = chr_ram_cart();
+        write_vram(&mut ppu, &mut cart, 0x0010, &[0xFF; 8]);
+        write_vram(&mut ppu, &mut cart, 0x0020, &[0xFF; 16]);
+        write_vram(&mut ppu, &mut cart, 0x2000, &[1, 2]);
+        // top-left quadrant uses palette 1
+        write_vram(&mut ppu, &mut cart, 0x23C0, &[0x01]);
+        write_vram(&mut ppu, &mut cart, 0x3F00, &[0x0F, 0x01, 0x02, 0x03, 0x0F, 0x16, 0x27, 0x38]);
+        (ppu, cart)
+    }
+
+    fn set_scroll(ppu: &mut PPU, cart: &mut cart::Cartridge, x: u8, y: u8) {
+        ppu.write_register(cart, 0x2000, 0x00).unwrap();
+        ppu.write_register(cart, 0x2005, x).unwrap();
+        ppu.write_register(cart, 0x2005, y).unwrap();
+    }
+
+    #[test]
+    fn renders_background_tiles() {
+        let (mut ppu, mut cart) = background_scene();
+        set_scroll(&mut ppu, &mut cart, 0, 0);
+        ppu.write_register(&mut cart, 0x2001, 0x0A).unwrap();
+        run_dots(&mut ppu, &mut cart, DOTS_PER_FRAME * 2);
+        let row = &ppu.frame()[0..24];
+        assert_eq!(row[..8], [0x16; 8]);
+        assert_eq!(row[8..16], [0x38; 8]);
+        assert_eq!(row[16..], [0x0F; 8]);
+        // the second tile row is blank
+        assert_eq!(ppu.frame()[8 * 256], 0x0F);
+    }
+
+    fn run_to(ppu: &mut PPU, cart: &mut cart::Cartridge, position: (u16, u16)) {
+        while ppu.position() != position {
+            ppu.step(cart).unwrap();
+        }
+    }
+
+    // tile 3 has its left half set to colour 1
+    fn sprite_scene(sprites: &[u8]) -> (PPU, cart::Cartridge) {
+        let (mut ppu, mut cart) = background_scene();
+        write_vram(&mut ppu, &mut cart, 0x0030, &[0xF0; 8]);
+        write_vram(&mut ppu, &mut cart, 0x3F10, &[0x0F, 0x2A]);
+        ppu.write_register(&mut cart, 0x2003, 0x00).unwrap();
+        for byte in sprites {
+            ppu.write_register(&mut cart, 0x2004, *byte).unwrap();
+        }
+        // the rest of OAM is parked below the screen
+        for _ in sprites.len()..256 {
+            ppu.write_register(&mut cart, 0x2004, 0xF0).unwrap();
+        }
+        set_scroll(&mut ppu, &mut cart, 0, 0);
+        ppu.write_register(&mut cart, 0x2001, 0x1E).unwrap();
+        (ppu, cart)
+    }
+
+    #[test]
+    fn renders_sprites_with_flip_and_priority() {
+        let (mut ppu, mut cart) = sprite_scene(&[
+            0, 3, 0x40, 20,
+            0, 3, 0x20, 4
+        ]);
+        run_dots(&mut ppu, &mut cart, DOTS_PER_FRAME * 2);
+        let row = &ppu.frame()[256..256 + 32];
+        // behind the opaque first tile
+        assert_eq!(row[4..8], [0x16; 4]);
+        assert_eq!(row[20..24], [0x0F; 4]);
+        assert_eq!(row[24..28], [0x2A; 4]);
+        // sprites start one line below their y
+        assert_eq!(ppu.frame()[24], 0x0F);
+    }
+
+    #[test]
+    fn sprite_zero_hit_at_first_overlap() {
+        let (mut ppu, mut cart) = sprite_scene(&[0, 3, 0x00, 4]);
+        run_dots(&mut ppu, &mut cart, DOTS_PER_FRAME);
+        run_to(&mut ppu, &mut cart, (1, 5));
+        assert_eq!(ppu.r_ppustatus & STATUS_SPRITE0, 0);
+        ppu.step(&mut cart).unwrap();
+        assert_ne!(ppu.r_ppustatus & STATUS_SPRITE0, 0);
+    }
+
+    #[test]
+    fn sprite_zero_never_hits_at_x_255() {
+        let (mut ppu, mut cart) = sprite_scene(&[0, 3, 0x00, 255]);
+        write_vram(&mut ppu, &mut cart, 0x201F, &[1]);
+        set_scroll(&mut ppu, &mut cart, 0, 0);
+        run_dots(&mut ppu, &mut cart, DOTS_PER_FRAME);
+        run_to(&mut ppu, &mut cart, (240, 0));
+        assert_eq!(ppu.r_ppustatus & STATUS_SPRITE0, 0);
+    }
+
+    #[test]
+    fn sprite_overflow_uses_diagonal_evaluation() {
+        let mut oam = Vec::new();
+        for _ in 0..8 {
+            oam.extend_from_slice(&[10, 3, 0, 100]);
+        }
+        // sprite 9 is off the line but its tile byte is compared as y
+        oam.extend_from_slice(&[200, 0, 0, 0, 200, 10, 0, 0]);
+        let (mut ppu, mut cart) = sprite_scene(&oam);
+        run_to(&mut ppu, &mut cart, (10, 258));
+        assert_ne!(ppu.r_ppustatus & STATUS_OVERFLOW, 0);
+
+        // a real ninth sprite is missed when the offset has moved on
+        oam.truncate(32);
+        oam.extend_from_slice(&[200, 0, 0, 0, 10, 0, 0, 0]);
+        let (mut ppu, mut cart) = sprite_scene(&oam);
+        run_to(&mut ppu, &mut cart, (10, 258));
+        assert_eq!(ppu.r_ppustatus & STATUS_OVERFLOW, 0);
+        assert_eq!(ppu.sprite_count, 8);
+    }
+
+    #[test]
+    fn vblank_flag_and_nmi() {
+        let mut ppu = PPU::new();
+        let mut cart = cart::Cartridge::new();
+        ppu.write_register(&mut cart, 0x2000, 0x80).unwrap();
+        run_to(&mut ppu, &mut cart, (241, 1));
+        assert_eq!(ppu.r_ppustatus & STATUS_VBLANK, 0);
+        assert!(!ppu.poll_frame_complete());
+        ppu.step(&mut cart).unwrap();
+        assert_ne!(ppu.r_ppustatus & STATUS_VBLANK, 0);
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.poll_frame_complete());
+        assert!(!ppu.poll_frame_complete());
+        run_to(&mut ppu, &mut cart, (261, 2));
+        assert_eq!(ppu.r_ppustatus & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank_raises_it() {
+        let mut ppu = PPU::new();
+        let mut cart = cart::Cartridge::new();
+        run_to(&mut ppu, &mut cart, (250, 0));
+        assert!(!ppu.poll_nmi());
+        ppu.write_register(&mut cart, 0x2000, 0x80).unwrap();
+        assert!(ppu.poll_nmi());
+        // rewriting the bit does not raise another
+        ppu.write_register(&mut cart, 0x2000, 0x80).unwrap();
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn status_read_races_vblank() {
+        let mut ppu = PPU::new();
+        let mut cart = cart::Cartridge::new();
+        ppu.write_register(&mut cart, 0x2000, 0x80).unwrap();
+        run_to(&mut ppu, &mut cart, (241, 1));
+        assert_eq!(ppu.read_register(&cart, 0x2002).unwrap() & STATUS_VBLANK, 0);
+        run_to(&mut ppu, &mut cart, (241, 10));
+        assert_eq!(ppu.r_ppustatus & STATUS_VBLANK, 0);
+        assert!(!ppu.poll_nmi());
+        // the frame is still reported
+        assert!(ppu.poll_frame_complete());
+
+        run_to(&mut ppu, &mut cart, (241, 2));
+        assert_ne!(ppu.read_register(&cart, 0x2002).unwrap() & STATUS_VBLANK, 0);
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot_while_rendering() {
+        let mut ppu = PPU::new();
+        let mut cart = chr_ram_cart();
+        ppu.write_register(&mut cart, 0x2001, 0x08).unwrap();
+        let mut lengths = Vec::new();
+        for _ in 0..4 {
+            run_to(&mut ppu, &mut cart, (241, 1));
+            let mut dots = 0;
+            loop {
+                ppu.step(&mut cart).unwrap();
+                dots += 1;
+                if ppu.position() == (241, 1) {
+                    break;
+                }
+            }
+            lengths.push(dots);
+        }
+        assert_eq!(lengths, vec![DOTS_PER_FRAME, DOTS_PER_FRAME - 1, DOTS_PER_FRAME, DOTS_PER_FRAME - 1]);
+
+        ppu.write_register(&mut cart, 0x2001, 0x00).unwrap();
+        run_to(&mut ppu, &mut cart, (240, 0));
+        run_to(&mut ppu, &mut cart, (240, 0));
+        let mut dots = 0;
+        loop {
+            ppu.step(&mut cart).unwrap();
+            dots += 1;
+            if ppu.position() == (240, 0) {
+                break;
+            }
+        }
+        assert_eq!(dots, DOTS_PER_FRAME);
+    }
+
+    #[test]
+    fn pal_frames_have_312_lines() {
+        let mut ppu = PPU::new();
+        let mut cart = chr_ram_cart();
+        ppu.set_region(Region::PAL);
+        ppu.write_register(&mut cart, 0x2001, 0x08).unwrap();
+        run_to(&mut ppu, &mut cart, (311, 340));
+        ppu.step(&mut cart).unwrap();
+        assert_eq!(ppu.position(), (0, 0));
+    }
+
+    #[test]
+    fn nametable_mirroring_modes() {
+        let mut ppu = PPU::new();
+        let mut cart = chr_ram_cart();
+        let mut layout = |cart: &mut cart::Cartridge, mirror_type| {
+            cart.set_mirror_type(mirror_type);
+            for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+                write_vram(&mut ppu, cart, *addr, &[i as u8]);
+            }
+            // $3000-$3EFF mirrors $2000-$2EFF
+            [0x3000u16, 0x2400, 0x2800, 0x2C00].iter()
+                .map(|addr| ppu.read(cart, *addr).unwrap())
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(layout(&mut cart, cart::MirrorType::HORIZONTAL), vec![1, 1, 3, 3]);
+        assert_eq!(layout(&mut cart, cart::MirrorType::VERTICAL), vec![2, 3, 2, 3]);
+        assert_eq!(layout(&mut cart, cart::MirrorType::SINGLEA), vec![3, 3, 3, 3]);
+        assert_eq!(layout(&mut cart, cart::MirrorType::SINGLEB), vec![3, 3, 3, 3]);
+        assert_eq!(layout(&mut cart, cart::MirrorType::FOURSCREEN), vec![0, 1, 2, 3]);
+        // the single-screen banks are distinct
+        cart.set_mirror_type(cart::MirrorType::SINGLEA);
+        write_vram(&mut ppu, &mut cart, 0x2000, &[0x55]);
+        assert_eq!(ppu.read(&cart, 0x2C00).unwrap(), 0x55);
+        cart.set_mirror_type(cart::MirrorType::SINGLEB);
+        assert_ne!(ppu.read(&cart, 0x2C00).unwrap(), 0x55);
+    }
+
+    #[test]
+    fn sprite_backdrop_entries_mirror_background() {
+        let mut ppu = PPU::new();
+        let mut cart = chr_ram_cart();
+        write_vram(&mut ppu, &mut cart, 0x3F10, &[0x21, 0x01, 0x02, 0x03, 0x24]);
+        assert_eq!(ppu.read(&cart, 0x3F00).unwrap(), 0x21);
+        assert_eq!(ppu.read(&cart, 0x3F04).unwrap(), 0x24);
+        assert_eq!(ppu.read(&cart, 0x3F11).unwrap(), 0x01);
+        assert_eq!(ppu.read(&cart, 0x3F01).unwrap(), 0x00);
+        // $3F20-$3FFF mirrors the 32 entries
+        assert_eq!(ppu.read(&cart, 0x3FF0).unwrap(), 0x21);
+    }
+
+    #[test]
+    fn greyscale_and_emphasis_are_recorded() {
+        let (mut ppu, mut cart) = background_scene();
+        set_scroll(&mut ppu, &mut cart, 0, 0);
+        ppu.write_register(&mut cart, 0x2001, 0xAB).unwrap();
+        run_dots(&mut ppu, &mut cart, DOTS_PER_FRAME * 2);
+        assert_eq!(ppu.frame()[0..2], [0x10, 0x10]);
+        assert_eq!(ppu.frame()[16], 0x00);
+        assert_eq!(ppu.frame_emphasis()[0], 0x05);
+    }
+
+    #[test]
+    fn fine_x_scroll_and_left_clipping() {
+        let (mut ppu, mut cart) = background_scene();
+        set_scroll(&mut ppu, &mut cart, 3, 0);
+        ppu.write_register(&mut cart, 0x2001, 0x08).unwrap();
+        run_dots(&mut ppu, &mut cart, DOTS_PER_FRAME * 2);
+        let row = &ppu.frame()[0..16];
+        assert_eq!(row[..8], [0x0F; 8]);
+        assert_eq!(row[8..13], [0x38; 5]);
+        assert_eq!(row[13..], [0x0F; 3]);
+    }
+
+    #[test]
+    fn scroll_and_addr_share_the_write_toggle() {
+        let mut ppu = PPU::new();
+        let mut cart = cart::Cartridge::new();
+        ppu.write_register(&mut cart, 0x2000, 0x03).unwrap();
+        ppu.write_register(&mut cart, 0x2005, 0x7D).unwrap();
+        assert_eq!((ppu.r_t, ppu.r_x, ppu.r_w), (0x0C0F, 0x05, true));
+        ppu.write_register(&mut cart, 0x2005, 0x5E).unwrap();
+        assert_eq!((ppu.r_t, ppu.r_w), (0x6D6F, false));
+        ppu.write_register(&mut cart, 0x2006, 0x3D).unwrap();
+        // a status read in between resets the toggle
+        ppu.read_register(&cart, 0x2002).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x23).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x45).unwrap();
+        assert_eq!((ppu.r_t, ppu.r_v), (0x2345, 0x2345));
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered() {
+        let mut ppu = PPU::new();
+        let mut cart = cart::Cartridge::new();
+        ppu.write_register(&mut cart, 0x2006, 0x24).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x00).unwrap();
+        ppu.write_register(&mut cart, 0x2007, 0x11).unwrap();
+        ppu.write_register(&mut cart, 0x2007, 0x22).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x24).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x00).unwrap();
+        let reads: Vec<u8> = (0..3).map(|_| ppu.read_register(&cart, 0x2007).unwrap()).collect();
+        assert_eq!(reads[1..], [0x11, 0x22]);
+
+        // palette reads come back right away
+        ppu.write_register(&mut cart, 0x2006, 0x3F).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x01).unwrap();
+        ppu.write_register(&mut cart, 0x2007, 0x2A).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x3F).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x01).unwrap();
+        assert_eq!(ppu.read_register(&cart, 0x2007).unwrap(), 0x2A);
+    }
+
+    #[test]
+    fn ppudata_increments_by_32() {
+        let mut ppu = PPU::new();
+        let mut cart = cart::Cartridge::new();
+        ppu.write_register(&mut cart, 0x2000, 0x04).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x20).unwrap();
+        ppu.write_register(&mut cart, 0x2006, 0x00).unwrap();
+        ppu.write_register(&mut cart, 0x2007, 0x01).unwrap();
+        ppu.write_register(&mut cart, 0x2007, 0x02).unwrap();
+        assert_eq!(ppu.r_v, 0x2040);
+        assert_eq!(ppu.vram[0x20], 0x02);
+    }
+
+    #[test]
+    fn status_read_clears_vblank() {
+        let mut ppu = PPU::new();
+        let mut cart = cart::Cartridge::new();
+        ppu.r_ppustatus = STATUS_VBLANK;
+        ppu.write_register(&mut cart, 0x2003, 0x10).unwrap();
+        ppu.write_register(&mut cart, 0x2004, 0x99).unwrap();
+        assert_eq!(ppu.read_register(&cart, 0x2002).unwrap(), 0x80 | 0x19);
+        assert_eq!(ppu.read_register(&cart, 0x2002).unwrap() & 0x80, 0);
+        ppu.write_register(&mut cart, 0x2003, 0x10).unwrap();
+        assert_eq!(ppu.read_register(&cart, 0x2004).unwrap(), 0x99);
+    }
+}
@@ -1,11 +1,6 @@
-use num_traits;
-
+#[allow(non_snake_case)]
 pub fn binaryBoolAnd<T>(left: T, right: T) -> bool
     where T: num_traits::Unsigned + std::ops::BitAnd<Output = T>{
-    if left & right == T::zero() {
-        false
-    }else {
-        true
-    }
+    left & right != T::zero()
 }
 
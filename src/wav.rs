@@ -0,0 +1,241 @@
+// 16-bit mono PCM RIFF/WAV output for recording the APU
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::Result;
+use crate::apu::{Apu, Channel, CHANNELS};
+use crate::audio;
+
+static HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    // writes a header with empty sizes, finish fills them in
+    pub fn new(mut out: W, sample_rate: u32) -> Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        // byte rate and block alignment of 16-bit mono
+        out.write_all(&(sample_rate * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            out,
+            samples: 0
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        for sample in samples {
+            self.out.write_all(&audio::to_i16(*sample).to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    // patches the RIFF and data chunk sizes for the samples so far
+    pub fn update_header(&mut self) -> Result<()> {
+        let data_size = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    // updates the header and hands the output back
+    pub fn finish(mut self) -> Result<W> {
+        self.update_header()?;
+        Ok(self.out)
+    }
+}
+
+// records the mixed output to `path` and, with stems, every channel to
+// `<name>.<channel>.wav` next to it. Only audio produced after start is
+// written, the host pulling samples does not take any away. Dropping it
+// without stop still leaves valid files with what was written so far.
+pub struct WavRecorder {
+    mix: WavWriter<BufWriter<File>>,
+    stems: Vec<(Channel, WavWriter<BufWriter<File>>)>,
+    // channel capture was switched on for the stems and goes off with them
+    owns_capture: bool,
+    finished: bool
+}
+
+impl WavRecorder {
+    pub fn start(apu: &mut Apu, path: &str, stems: bool) -> Result<Self> {
+        let sample_rate = apu.audio().sample_rate();
+        let mix = WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)?;
+        let mut recorder = WavRecorder {
+            mix,
+            stems: Vec::new(),
+            owns_capture: false,
+            finished: false
+        };
+        if stems {
+            for channel in CHANNELS.iter() {
+                let file = File::create(stem_path(path, *channel))?;
+                recorder.stems.push((*channel, WavWriter::new(BufWriter::new(file), sample_rate)?));
+            }
+            if !apu.channel_capture() {
+                apu.set_channel_capture(true);
+                recorder.owns_capture = true;
+            }
+        }
+        apu.audio_mut().set_tap(true);
+        for (channel, _) in recorder.stems.iter() {
+            if let Some(audio) = apu.channel_audio_mut(*channel) {
+                audio.set_tap(true);
+            }
+        }
+        Ok(recorder)
+    }
+
+    // writes what the APU produced since the last call
+    pub fn write_pending(&mut self, apu: &mut Apu) -> Result<()> {
+        self.mix.write_samples(&apu.audio_mut().take_tapped())?;
+        for (channel, writer) in self.stems.iter_mut() {
+            if let Some(audio) = apu.channel_audio_mut(*channel) {
+                writer.write_samples(&audio.take_tapped())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop(mut self, apu: &mut Apu) -> Result<()> {
+        self.write_pending(apu)?;
+        apu.audio_mut().set_tap(false);
+        for (channel, _) in self.stems.iter() {
+            if let Some(audio) = apu.channel_audio_mut(*channel) {
+                audio.set_tap(false);
+            }
+        }
+        if self.owns_capture {
+            apu.set_channel_capture(false);
+        }
+        self.update_headers()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    fn update_headers(&mut self) -> Result<()> {
+        for (_, writer) in self.stems.iter_mut() {
+            writer.update_header()?;
+        }
+        self.mix.update_header()
+    }
+}
+
+impl Drop for WavRecorder {
+    // best effort, there is nobody left to report an error to
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.update_headers();
+        }
+    }
+}
+
+// "music.wav" becomes "music.pulse1.wav"
+fn stem_path(path: &str, channel: Channel) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("audio");
+    path.with_file_name(format!("{}.{}.wav", stem, channel.name()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_pcm_header_and_samples() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 48_000).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+        assert_eq!(data.len(), 50);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([data[4], data[5], data[6], data[7]]), 42);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes([data[24], data[25], data[26], data[27]]), 48_000);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32::from_le_bytes([data[40], data[41], data[42], data[43]]), 6);
+        assert_eq!(&data[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn stems_are_named_after_channels() {
+        assert_eq!(stem_path("out/music.wav", Channel::Pulse1), "out/music.pulse1.wav");
+        assert_eq!(stem_path("take", Channel::DMC), "take.dmc.wav");
+    }
+
+    #[test]
+    fn records_only_after_start() {
+        let dir = std::env::temp_dir().join(format!("nes-lib-wav-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("take.wav").to_string_lossy().into_owned();
+
+        let mut apu = Apu::new();
+        for _ in 0..10_000 {
+            apu.step();
+        }
+        apu.end_audio_frame();
+        let before = apu.audio().samples_available();
+        let mut recorder = WavRecorder::start(&mut apu, &path, true).unwrap();
+        for _ in 0..10_000 {
+            apu.step();
+        }
+        apu.end_audio_frame();
+        recorder.write_pending(&mut apu).unwrap();
+        let recorded = apu.audio().samples_available() - before;
+        recorder.stop(&mut apu).unwrap();
+        assert!(!apu.channel_capture());
+
+        let mix = std::fs::read(&path).unwrap();
+        assert_eq!(mix.len(), 44 + recorded * 2);
+        let stem = std::fs::read(stem_path(&path, Channel::Triangle)).unwrap();
+        // the stem's resampler starts at a different fraction of a sample
+        assert!((stem.len() as i64 - mix.len() as i64).abs() <= 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_a_recorder_finishes_the_files() {
+        let dir = std::env::temp_dir().join(format!("nes-lib-wav-drop-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("take.wav").to_string_lossy().into_owned();
+
+        let mut apu = Apu::new();
+        let mut recorder = WavRecorder::start(&mut apu, &path, true).unwrap();
+        for _ in 0..10_000 {
+            apu.step();
+        }
+        apu.end_audio_frame();
+        recorder.write_pending(&mut apu).unwrap();
+        drop(recorder);
+
+        for file in [path.clone(), stem_path(&path, Channel::Noise)] {
+            let data = std::fs::read(file).unwrap();
+            let size = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+            assert!(data.len() > 44);
+            assert_eq!(size(4) as usize, data.len() - 8);
+            assert_eq!(size(40) as usize, data.len() - 44);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}